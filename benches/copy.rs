@@ -0,0 +1,120 @@
+//! Compares the copy engines with `fs::copy` on one large file and on many small ones.
+//!
+//! The files are written under the system temp directory, so `TMPDIR` picks the
+//! filesystem being measured: `TMPDIR=/dev/shm cargo bench --bench copy` for tmpfs,
+//! or a directory on an ext4, XFS or Btrfs disk. Add `--features io-uring` to include
+//! the io_uring engine.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// Their unit tests are compiled in but never run here
+#[allow(dead_code, unused_imports)]
+#[path = "../src/args.rs"]
+mod args;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/copy.rs"]
+mod copy;
+#[allow(dead_code)]
+#[path = "../src/throttle.rs"]
+mod throttle;
+
+use args::CopyEngine;
+
+const LARGE_FILE: usize = 64 * 1024 * 1024;
+const SMALL_FILE: usize = 4 * 1024;
+const SMALL_FILES: usize = 1000;
+
+const ENGINES: [(&str, CopyEngine); 5] = [
+    ("std", CopyEngine::Std),
+    ("auto", CopyEngine::Auto),
+    ("copy_file_range", CopyEngine::CopyFileRange),
+    ("sendfile", CopyEngine::Sendfile),
+    ("buffered", CopyEngine::Buffered),
+];
+
+/// A scratch directory, removed when dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("yee-haw-bench-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("from")).expect("create the bench source directory");
+        fs::create_dir_all(dir.join("to")).expect("create the bench destination directory");
+        Self(dir)
+    }
+
+    /// Write `count` files of `size` bytes, returning their sources and destinations
+    fn files(&self, count: usize, size: usize) -> Vec<(PathBuf, PathBuf)> {
+        (0..count)
+            .map(|index| {
+                let name = format!("file_{}.bin", index);
+                let data: Vec<u8> = (0..size).map(|byte| (byte * 31 + index) as u8).collect();
+                let source = self.0.join("from").join(&name);
+                fs::write(&source, data).expect("write a bench file");
+                (source, self.0.join("to").join(&name))
+            })
+            .collect()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn large_file(c: &mut Criterion) {
+    let scratch = Scratch::new("large");
+    let files = scratch.files(1, LARGE_FILE);
+    let (source, destination) = &files[0];
+
+    let mut group = c.benchmark_group("large_file");
+    group.sample_size(10).throughput(Throughput::Bytes(LARGE_FILE as u64));
+    for (name, engine) in ENGINES {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| copy::copy_file(source, destination, engine).expect("copy the large file"))
+        });
+    }
+    if copy::IO_URING {
+        group.bench_function(BenchmarkId::from_parameter("io_uring"), |b| {
+            b.iter(|| copy::copy_file(source, destination, CopyEngine::IoUring).expect("copy the large file"))
+        });
+    }
+    group.finish();
+}
+
+fn small_files(c: &mut Criterion) {
+    let scratch = Scratch::new("small");
+    let files = scratch.files(SMALL_FILES, SMALL_FILE);
+    let pairs: Vec<(&Path, &Path)> = files.iter().map(|(from, to)| (from.as_path(), to.as_path())).collect();
+
+    let mut group = c.benchmark_group("small_files");
+    group.sample_size(10).throughput(Throughput::Elements(SMALL_FILES as u64));
+    for (name, engine) in ENGINES {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                for (source, destination) in &pairs {
+                    copy::copy_file(source, destination, engine).expect("copy a small file");
+                }
+            })
+        });
+    }
+    if copy::IO_URING {
+        // The mover hands io_uring workers up to 32 small files at once
+        group.bench_function(BenchmarkId::from_parameter("io_uring"), |b| {
+            b.iter(|| {
+                for batch in pairs.chunks(32) {
+                    for result in copy::copy_batch(batch) {
+                        result.expect("copy a small file");
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, large_file, small_files);
+criterion_main!(benches);
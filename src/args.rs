@@ -1,4 +1,4 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 
 /// Smart file wrangler for the terminal
 #[derive(Parser, Debug, Clone)]
@@ -13,7 +13,7 @@ pub struct YeeArgs {
     pub query: String,
 
     /// Destination directory to move files to
-    #[arg(short = 'd', long, default_value = "./out")]
+    #[arg(short = 'd', long, default_value = "./out", global = true)]
     pub destination_dir: String,
 
     /// Perform a dry run (don't actually move files)
@@ -35,6 +35,153 @@ pub struct YeeArgs {
     /// Copy files instead of moving them
     #[arg(short = 'c', long, default_value_t = false)]
     pub copy_mode: bool,
+
+    /// Recreate hardlinks between source files as hardlinks in the destination
+    #[arg(long, default_value_t = false)]
+    pub preserve_links: bool,
+
+    /// Review duplicates and the destination tree in a terminal UI before moving files
+    #[arg(long, default_value_t = false)]
+    pub review: bool,
+
+    /// Detect whole directories that are identical to, or contained in, other directories
+    #[arg(long, default_value_t = false)]
+    pub dir_dupes: bool,
+
+    /// Report directory pairs whose file contents overlap by at least this ratio (0.0-1.0)
+    #[arg(long, value_name = "THRESHOLD", value_parser = parse_ratio)]
+    pub similar_dirs: Option<f64>,
+
+    /// Also treat visually similar images (resized, recompressed, re-exported) as duplicates
+    #[arg(long, default_value_t = false)]
+    pub similar_images: bool,
+
+    /// Perceptual hash used by --similar-images
+    #[arg(long, value_enum, default_value_t = ImageHashStyle::Dhash)]
+    pub image_hash: ImageHashStyle,
+
+    /// Maximum Hamming distance (out of 64 bits) between similar images
+    #[arg(long, default_value_t = 10)]
+    pub image_distance: u32,
+
+    /// Report text files whose word shingles overlap by at least this ratio (0.0-1.0)
+    #[arg(long, value_name = "THRESHOLD", value_parser = parse_ratio)]
+    pub similar_text: Option<f64>,
+
+    /// What to compare when detecting duplicates
+    #[arg(long, value_enum, default_value_t = ContentKey::Whole)]
+    pub content_key: ContentKey,
+
+    /// Use a different content key for matching files, as PATTERN=KEY (e.g. mp3=audio-stream, eml=message-id).
+    /// PATTERN is an extension or a glob on the file name; can be repeated
+    #[arg(long = "dedup-key", value_name = "PATTERN=KEY", value_parser = parse_dedup_rule)]
+    pub dedup_keys: Vec<DedupRule>,
+
+    /// Only treat files of at least this many bytes as duplicates; smaller files are always kept.
+    /// At least 1, empty files are never deduplicated
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_dedup_size: u64,
+
+    /// What to do with empty files, which are never deduplicated
+    #[arg(long, value_enum, default_value_t = EmptyFiles::KeepAll)]
+    pub empty_files: EmptyFiles,
+
+    /// Which copy of a duplicate to keep as the original
+    #[arg(long, value_enum, default_value_t = KeepPolicy::First)]
+    pub keep_policy: KeepPolicy,
+
+    /// Write a YAML report of all detected duplicates to this path
+    #[arg(long)]
+    pub report: Option<String>,
+
+    /// Write the planned operations as YAML to this path (useful with --dry)
+    #[arg(long)]
+    pub plan_out: Option<String>,
+
+    /// What to do when several files map to the same destination path
+    #[arg(long, value_enum, default_value_t = CollisionPolicy::Suffix)]
+    pub on_collision: CollisionPolicy,
+
+    /// What to do when a file already exists at its destination
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::Rename)]
+    pub on_conflict: ConflictPolicy,
+
+    /// File attributes to carry over to copies, comma separated
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Preserve::Times, Preserve::Mode])]
+    pub preserve: Vec<Preserve>,
+
+    /// Write a YAML report of what the run did (collisions, conflicts, ...) to this path
+    #[arg(long)]
+    pub run_report: Option<String>,
+
+    /// Stop the run at the first failed operation
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Stop the run once this many operations have failed
+    #[arg(long)]
+    pub max_errors: Option<usize>,
+
+    /// Number of files to copy or move at the same time
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub copy_jobs: usize,
+
+    /// Copy or move at most this many files at once to or from the same device
+    #[arg(long, value_name = "N")]
+    pub device_jobs: Option<usize>,
+
+    /// How file data is copied; `auto` lets the kernel copy it where it can
+    #[arg(long, value_enum, default_value_t = CopyEngine::Auto)]
+    pub copy_engine: CopyEngine,
+
+    /// Read and copy at most this many bytes per second, e.g. `20M` (K, M and G suffixes)
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub bwlimit: Option<u64>,
+
+    /// Hash at most this many files, and run at most this many operations, per second
+    #[arg(long, value_name = "N")]
+    pub ops_limit: Option<u64>,
+
+    /// Run at idle I/O priority and the lowest CPU priority, to stay out of the way
+    #[arg(long, default_value_t = false)]
+    pub nice: bool,
+
+    /// Space to leave free on each destination filesystem, e.g. `1G` (K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "100M")]
+    pub reserve: u64,
+
+    /// Don't check free space and permissions before running the plan
+    #[arg(long, default_value_t = false)]
+    pub no_preflight: bool,
+
+    /// What to do when another run holds the destination's lock
+    #[arg(long, value_enum, default_value_t = LockPolicy::Fail)]
+    pub on_locked: LockPolicy,
+
+    /// Print the plan (dry runs) or each executed operation and the final stats on stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Don't show progress or the summary table
+    #[arg(long, global = true)]
+    pub quiet: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Reverse a previous run using its journal in the destination directory
+    Undo {
+        /// Id of the run, as logged when it started
+        run_id: String,
+    },
+    /// Finish an interrupted run, following the plan it saved when it started
+    Resume {
+        /// Id of the run, as logged when it started
+        run_id: String,
+    },
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
@@ -59,8 +206,218 @@ pub enum GroupStyle {
     Incremental,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ImageHashStyle {
+    /// Difference hash, fast and robust to resizing and recompression
+    Dhash,
+    /// DCT-based perceptual hash, more robust to brightness and contrast changes
+    Phash,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ContentKey {
+    /// Hash the whole file
+    Whole,
+    /// Compare file name and size only, without reading the contents
+    NameSize,
+    /// Hash only the audio stream, ignoring ID3 tags and FLAC metadata
+    AudioStream,
+    /// Hash only the image data of JPEG, PNG, WebP and GIF files, ignoring EXIF/XMP and other metadata
+    ImageData,
+    /// Hash text files with whitespace and line endings normalized, so formatting-only changes match
+    Text,
+    /// Compare emails by their Message-ID header
+    MessageId,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum EmptyFiles {
+    /// Leave empty files where they are
+    Ignore,
+    /// Organize every empty file like any other original
+    KeepAll,
+    /// Organize every empty file and list them in the report
+    Report,
+}
+
+/// A `--dedup-key` rule: files matching `pattern` use `key` instead of the default
+#[derive(Clone, Debug)]
+pub struct DedupRule {
+    /// An extension (`mp3`) or a glob on the file name (`IMG_*.jpg`)
+    pub pattern: String,
+    pub key: ContentKey,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum KeepPolicy {
+    /// Keep the first copy found
+    First,
+    /// Keep the copy with the most metadata (the largest copy, for files other than JPEG and PNG)
+    MostMetadata,
+    /// Keep the copy with the least metadata (the smallest copy, for files other than JPEG and PNG)
+    LeastMetadata,
+    /// Keep the most recently modified copy
+    Newest,
+}
+
+/// Parse a ratio between 0.0 and 1.0 inclusive
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    let ratio: f64 = value.parse().map_err(|_| format!("'{}' is not a number", value))?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err(format!("{} is not between 0.0 and 1.0", ratio))
+    }
+}
+
+/// Parse a number of bytes per second, with an optional K, M or G (binary) suffix
+fn parse_rate(value: &str) -> Result<u64, String> {
+    match parse_size(value)? {
+        0 => Err(format!("{} is less than one byte per second", value)),
+        rate => Ok(rate),
+    }
+}
+
+/// Parse a number of bytes, with an optional K, M or G (binary) suffix
+fn parse_size(value: &str) -> Result<u64, String> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match value[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        suffix => return Err(format!("unknown unit '{}', use K, M or G", suffix)),
+    };
+    let size: f64 = digits.parse().map_err(|_| format!("'{}' is not a number", digits))?;
+    if size < 0.0 {
+        return Err(format!("{} is negative", value));
+    }
+    Ok((size * multiplier as f64) as u64)
+}
+
+/// Parse a PATTERN=KEY dedup rule
+fn parse_dedup_rule(value: &str) -> Result<DedupRule, String> {
+    let (pattern, key) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not in PATTERN=KEY form", value))?;
+    if pattern.is_empty() {
+        return Err(format!("'{}' has an empty pattern", value));
+    }
+    let key = ContentKey::from_str(key, true)?;
+    Ok(DedupRule {
+        pattern: pattern.to_string(),
+        key,
+    })
+}
+
 impl YeeArgs {
     pub fn parse_args() -> Self {
         Self::parse()
     }
 }
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum CollisionPolicy {
+    /// Add a counter to the later file's name (`name_1.ext`)
+    Suffix,
+    /// Leave the later file where it is
+    Skip,
+    /// Stop before anything is changed
+    Error,
+    /// Add the start of the later file's hash to its name (`name_1a2b3c4d.ext`)
+    HashSuffix,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ConflictPolicy {
+    /// Leave the source file where it is
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Write the file next to the existing one with a counter (`name_1.ext`)
+    Rename,
+    /// Replace the existing file only if the source was modified more recently
+    IfNewer,
+    /// Replace the existing file only if its hash differs; an identical file counts as already organized
+    IfDifferent,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum OutputFormat {
+    /// Plain text for people, the plan is printed on dry runs
+    Text,
+    /// A single JSON document, printed once the run is over
+    Json,
+    /// One JSON record per line, printed as each operation happens
+    Jsonl,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum CopyEngine {
+    /// `copy_file_range`, then `sendfile`, then a buffered copy, whichever works first
+    Auto,
+    /// `copy_file_range` (Linux), which can reflink or copy server-side on NFS and SMB
+    CopyFileRange,
+    /// `sendfile` (Linux)
+    Sendfile,
+    /// Read into a buffer and write it out
+    Buffered,
+    /// The standard library's `io::copy`
+    Std,
+    /// Batches of small files through io_uring (Linux, built with `--features io-uring`)
+    IoUring,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum LockPolicy {
+    /// Wait for the other run to finish
+    Wait,
+    /// Stop without changing anything
+    Fail,
+    /// Carry on as a dry run, printing the plan without writing anything
+    ReadOnly,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Preserve {
+    /// Access and modification times
+    Times,
+    /// Permission bits
+    Mode,
+    /// Owning user and group (usually needs root)
+    Owner,
+    /// Extended attributes, such as `user.*` tags and SELinux labels
+    Xattr,
+    /// POSIX access control lists
+    Acl,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_with_binary_units() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("1.5K"), Ok(1536));
+        assert_eq!(parse_size("100m"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("2MiB"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1GB"), Ok(1024 * 1024 * 1024));
+
+        assert!(parse_size("10T").unwrap_err().contains("unknown unit"));
+        assert!(parse_size("inf").is_err());
+        assert!(parse_size("").unwrap_err().contains("not a number"));
+        assert!(parse_size("K").unwrap_err().contains("not a number"));
+        assert!(parse_size("-1K").unwrap_err().contains("negative"));
+    }
+
+    #[test]
+    fn rates_are_at_least_one_byte_per_second() {
+        assert_eq!(parse_rate("1"), Ok(1));
+        assert_eq!(parse_rate("10M"), Ok(10 * 1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("0.5").is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use crate::args::CopyEngine;
+use crate::throttle;
+
+/// Files up to this size are copied in batches by the io_uring engine
+pub const SMALL_FILE: u64 = 1024 * 1024;
+/// Whether this build has the io_uring engine (the `io-uring` feature, Linux only)
+pub const IO_URING: bool = cfg!(all(target_os = "linux", feature = "io-uring"));
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Copies the data of `source` over `destination`, returning the number of bytes copied.
+/// A new file gets the default mode; the source's permissions are only carried over by
+/// `--preserve mode`.
+///
+/// `Auto` lets the kernel copy the data without it passing through userspace:
+/// `copy_file_range` first (which can also reflink or copy server-side on NFS and
+/// SMB), then `sendfile`, then a plain buffered copy. The other engines force one
+/// method, falling back to the buffered copy where the kernel can't do it.
+///
+/// Every engine but `Std`, which is only throttled once the file is copied, copies in
+/// pieces small enough for `--bwlimit`.
+pub fn copy_file(source: &Path, destination: &Path, engine: CopyEngine) -> io::Result<u64> {
+    match engine {
+        CopyEngine::Std => {
+            let (mut reader, mut writer, _) = open(source, destination)?;
+            let copied = io::copy(&mut reader, &mut writer)?;
+            throttle::bytes(copied);
+            Ok(copied)
+        }
+        CopyEngine::IoUring => copy_batch(&[(source, destination)]).pop().unwrap_or(Ok(0)),
+        _ => {
+            let (mut reader, mut writer, len) = open(source, destination)?;
+            copy_open(&mut reader, &mut writer, len, engine)
+        }
+    }
+}
+
+/// Copies each `(source, destination)` pair, returning a result per pair.
+///
+/// With the io_uring engine built in, the small files of the batch are read and then
+/// written with one submission each, instead of several system calls per file. Larger
+/// files, and every file when io_uring is missing or refused, are copied like `Auto`.
+pub fn copy_batch(pairs: &[(&Path, &Path)]) -> Vec<io::Result<u64>> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        uring::copy_batch(pairs)
+    }
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    {
+        pairs
+            .iter()
+            .map(|(source, destination)| copy_file(source, destination, CopyEngine::Auto))
+            .collect()
+    }
+}
+
+/// Open the source for reading and create (or truncate) the destination
+fn open(source: &Path, destination: &Path) -> io::Result<(File, File, u64)> {
+    let reader = File::open(source)?;
+    let metadata = reader.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the source is not a regular file"));
+    }
+    let writer = OpenOptions::new().write(true).create(true).truncate(true).open(destination)?;
+    Ok((reader, writer, metadata.len()))
+}
+
+/// Copy between two open files with the kernel methods the engine allows, then buffered
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn copy_open(reader: &mut File, writer: &mut File, len: u64, engine: CopyEngine) -> io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        if matches!(engine, CopyEngine::Auto | CopyEngine::CopyFileRange) {
+            if let Some(copied) = linux::copy_file_range(reader, writer, len)? {
+                return Ok(copied);
+            }
+        }
+        if matches!(engine, CopyEngine::Auto | CopyEngine::Sendfile) {
+            if let Some(copied) = linux::sendfile(reader, writer, len)? {
+                return Ok(copied);
+            }
+        }
+    }
+    buffered(reader, writer, len)
+}
+
+/// Read into a buffer and write it out, for filesystems the kernel can't copy between
+fn buffered(reader: &mut File, writer: &mut File, len: u64) -> io::Result<u64> {
+    // No bigger than the file (plus one byte to see its end), small files are common
+    let largest = throttle::chunk(BUFFER_SIZE);
+    let size = usize::try_from(len.saturating_add(1)).unwrap_or(largest).clamp(8 * 1024, largest);
+    let mut buffer = vec![0; size];
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+        throttle::bytes(read as u64);
+        copied += read as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use crate::throttle;
+
+    /// Largest amount asked of the kernel per call
+    const CHUNK: usize = 1 << 30;
+
+    /// Errors that mean the kernel can't copy between these files, rather than that the copy failed
+    fn unsupported(error: &io::Error) -> bool {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::EPERM | libc::EBADF)
+        )
+    }
+
+    /// Copy with `copy_file_range(2)`. `None` if the kernel can't, before anything was written.
+    pub fn copy_file_range(reader: &File, writer: &File, len: u64) -> io::Result<Option<u64>> {
+        let mut copied = 0;
+        loop {
+            // Null offsets use and advance the file positions, so a fallback starts where this stopped
+            let result = unsafe {
+                libc::copy_file_range(
+                    reader.as_raw_fd(),
+                    ptr::null_mut(),
+                    writer.as_raw_fd(),
+                    ptr::null_mut(),
+                    throttle::chunk(CHUNK),
+                    0,
+                )
+            };
+            match finish_call(result, copied, len)? {
+                Step::Copied(count) => copied += count,
+                Step::Done(result) => return Ok(result),
+            }
+        }
+    }
+
+    /// Copy with `sendfile(2)`. `None` if the kernel can't, before anything was written.
+    pub fn sendfile(reader: &File, writer: &File, len: u64) -> io::Result<Option<u64>> {
+        let mut copied = 0;
+        loop {
+            let chunk = throttle::chunk(CHUNK);
+            let result = unsafe { libc::sendfile(writer.as_raw_fd(), reader.as_raw_fd(), ptr::null_mut(), chunk) };
+            match finish_call(result, copied, len)? {
+                Step::Copied(count) => copied += count,
+                Step::Done(result) => return Ok(result),
+            }
+        }
+    }
+
+    enum Step {
+        Copied(u64),
+        Done(Option<u64>),
+    }
+
+    /// Interpret the return value of one copy call, `copied` bytes into a file of `len`
+    fn finish_call(result: isize, copied: u64, len: u64) -> io::Result<Step> {
+        if result < 0 {
+            let error = io::Error::last_os_error();
+            return match error {
+                _ if error.kind() == io::ErrorKind::Interrupted => Ok(Step::Copied(0)),
+                _ if copied == 0 && unsupported(&error) => Ok(Step::Done(None)),
+                _ => Err(error),
+            };
+        }
+        if result > 0 {
+            throttle::bytes(result as u64);
+            return Ok(Step::Copied(result as u64));
+        }
+        // Some filesystems (procfs, sysfs) report an empty file that isn't, let the next method read it
+        if copied == 0 && len > 0 {
+            return Ok(Step::Done(None));
+        }
+        Ok(Step::Done(Some(copied)))
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring {
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io;
+    use std::ops::Range;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use io_uring::{opcode, types, IoUring};
+    use log::debug;
+    use crate::args::CopyEngine;
+    use crate::throttle;
+    use super::{copy_file, open, SMALL_FILE};
+
+    /// Files submitted to the ring at once
+    const RING_SIZE: usize = 64;
+
+    thread_local! {
+        /// Each copy worker sets up its ring once and keeps it, `None` if the kernel refused
+        static RING: RefCell<Option<Option<IoUring>>> = const { RefCell::new(None) };
+    }
+
+    /// A small file read into memory, waiting to be written out
+    struct Pending {
+        index: usize,
+        reader: File,
+        writer: File,
+        buffer: Vec<u8>,
+    }
+
+    /// The ring failed and was dropped; `in_flight` if the kernel may still be using the
+    /// buffers and files of the entries, which then must never be freed
+    struct Broken {
+        in_flight: bool,
+    }
+
+    pub fn copy_batch(pairs: &[(&Path, &Path)]) -> Vec<io::Result<u64>> {
+        // Under `--bwlimit` a submission holds at most a tenth of a second's worth of
+        // data, like the pieces the other engines copy, so it never runs far ahead of
+        // the limit and then stalls
+        let budget = throttle::chunk(RING_SIZE * SMALL_FILE as usize) as u64;
+        let mut results: Vec<io::Result<u64>> = pairs.iter().map(|_| Ok(0)).collect();
+        let mut pending = Vec::new();
+        for (index, (source, destination)) in pairs.iter().enumerate() {
+            match open(source, destination) {
+                Ok((reader, writer, len)) if len > 0 && len <= SMALL_FILE.min(budget) => {
+                    let buffer = vec![0; len as usize];
+                    pending.push(Pending { index, reader, writer, buffer });
+                }
+                Ok(_) => results[index] = copy_file(source, destination, CopyEngine::Auto),
+                Err(e) => results[index] = Err(e),
+            }
+        }
+        if pending.is_empty() {
+            return results;
+        }
+
+        let mut leak = false;
+        let sizes: Vec<u64> = pending.iter().map(|file| file.buffer.len() as u64).collect();
+        for range in submissions(&sizes, budget) {
+            let chunk = &mut pending[range];
+            let outcomes = RING.with_borrow_mut(|slot| {
+                let ring = slot.get_or_insert_with(|| match IoUring::new(RING_SIZE as u32) {
+                    Ok(ring) => Some(ring),
+                    Err(e) => {
+                        debug!("io_uring is not available ({}), copying without it", e);
+                        None
+                    }
+                });
+                let outcome = match ring.as_mut() {
+                    Some(ring) => copy_chunk(ring, chunk),
+                    None => Ok(chunk.iter().map(|_| Err(io::ErrorKind::Unsupported.into())).collect()),
+                };
+                if outcome.is_err() {
+                    // Entries may be left queued or in flight, so the ring is never used again
+                    *slot = Some(None);
+                }
+                outcome
+            });
+            let outcomes = match outcomes {
+                Ok(outcomes) => outcomes,
+                Err(Broken { in_flight: false }) => chunk.iter().map(|_| Err(io::ErrorKind::Other.into())).collect(),
+                Err(Broken { in_flight: true }) => {
+                    // Writing these files again could race the kernel's writes, fail them instead
+                    leak = true;
+                    for file in chunk.iter() {
+                        let error = io::Error::other("io_uring stopped with the copy in flight");
+                        results[file.index] = Err(error);
+                    }
+                    continue;
+                }
+            };
+            for (file, outcome) in chunk.iter().zip(outcomes) {
+                // A short read or write, or no ring: start this file over without io_uring
+                results[file.index] = outcome.or_else(|_| {
+                    let (source, destination) = pairs[file.index];
+                    copy_file(source, destination, CopyEngine::Auto)
+                });
+            }
+        }
+        if leak {
+            // The kernel may still read from or write to the buffers and files
+            std::mem::forget(pending);
+        }
+        results
+    }
+
+    /// Split files of these sizes into runs of at most `RING_SIZE` files and `budget`
+    /// bytes, each submitted at once
+    fn submissions(sizes: &[u64], budget: u64) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        let (mut start, mut bytes) = (0, 0);
+        for (index, &size) in sizes.iter().enumerate() {
+            if index > start && (index - start == RING_SIZE || bytes + size > budget) {
+                ranges.push(start..index);
+                (start, bytes) = (index, 0);
+            }
+            bytes += size;
+        }
+        if start < sizes.len() {
+            ranges.push(start..sizes.len());
+        }
+        ranges
+    }
+
+    /// Read every file of the chunk in one submission, then write them all in another
+    fn copy_chunk(ring: &mut IoUring, chunk: &mut [Pending]) -> Result<Vec<io::Result<u64>>, Broken> {
+        throttle::bytes(chunk.iter().map(|file| file.buffer.len() as u64).sum());
+        let reads: Vec<_> = chunk
+            .iter_mut()
+            .map(|file| {
+                let fd = types::Fd(file.reader.as_raw_fd());
+                opcode::Read::new(fd, file.buffer.as_mut_ptr(), file.buffer.len() as u32).offset(0).build()
+            })
+            .collect();
+        // A file that changed size since it was opened is read short, and copied again without io_uring
+        let read: Vec<io::Result<u64>> = submit(ring, &reads)?
+            .into_iter()
+            .zip(chunk.iter())
+            .map(|(read, file)| match read? {
+                count if count == file.buffer.len() as u64 => Ok(count),
+                _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short read")),
+            })
+            .collect();
+
+        let writes: Vec<_> = chunk
+            .iter()
+            .zip(&read)
+            .map(|(file, read)| {
+                let fd = types::Fd(file.writer.as_raw_fd());
+                // Files whose read failed get a zero-length write, so the completions still line up
+                let len = if read.is_ok() { file.buffer.len() as u32 } else { 0 };
+                opcode::Write::new(fd, file.buffer.as_ptr(), len).offset(0).build()
+            })
+            .collect();
+        let written = submit(ring, &writes)?;
+
+        Ok(chunk
+            .iter()
+            .zip(read.into_iter().zip(written))
+            .map(|(file, (read, written))| {
+                read?;
+                let written = written?;
+                if written != file.buffer.len() as u64 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "short write"));
+                }
+                Ok(written)
+            })
+            .collect())
+    }
+
+    /// Submit the entries and wait for every one of them to complete, a result per
+    /// entry in order. Fails only if the ring stopped working, which then can't be used again.
+    fn submit(ring: &mut IoUring, entries: &[io_uring::squeue::Entry]) -> Result<Vec<io::Result<u64>>, Broken> {
+        let mut results: Vec<io::Result<u64>> = entries.iter().map(|_| Err(io::ErrorKind::Other.into())).collect();
+        {
+            let mut submission = ring.submission();
+            for (index, entry) in entries.iter().enumerate() {
+                // Safe: this returns once every entry completed, or reports the ring broken
+                // with entries in flight so the caller never frees their buffers and files
+                if unsafe { submission.push(&entry.clone().user_data(index as u64)) }.is_err() {
+                    return Err(Broken { in_flight: false });
+                }
+            }
+        }
+        let mut waiting = entries.len();
+        while waiting > 0 {
+            if let Err(e) = ring.submit_and_wait(waiting) {
+                match e.raw_os_error() {
+                    // A signal (SIGINT, SIGTERM) came in, or the kernel wants completions reaped first
+                    Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) => {}
+                    _ => {
+                        debug!("io_uring submission failed: {}", e);
+                        let queued = ring.submission().len();
+                        return Err(Broken { in_flight: waiting > queued });
+                    }
+                }
+            }
+            for completion in ring.completion() {
+                waiting = waiting.saturating_sub(1);
+                let Some(result) = results.get_mut(completion.user_data() as usize) else {
+                    continue;
+                };
+                *result = match completion.result() {
+                    count if count < 0 => Err(io::Error::from_raw_os_error(-count)),
+                    count => Ok(count as u64),
+                };
+            }
+        }
+        Ok(results)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn submissions_stay_within_the_budget() {
+            let sizes = [40, 40, 30, 100, 10, 10];
+            assert_eq!(submissions(&sizes, 80), vec![0..2, 2..3, 3..4, 4..6]);
+            assert_eq!(submissions(&sizes, u64::MAX), vec![0..6]);
+            assert_eq!(submissions(&[1; RING_SIZE + 1], u64::MAX), vec![0..RING_SIZE, RING_SIZE..RING_SIZE + 1]);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn copies_data_but_not_permissions() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("script.sh");
+        fs::write(&source, b"#!/bin/sh\necho yee-haw\n").unwrap();
+        fs::set_permissions(&source, Permissions::from_mode(0o751)).unwrap();
+
+        for engine in [
+            CopyEngine::Auto,
+            CopyEngine::CopyFileRange,
+            CopyEngine::Sendfile,
+            CopyEngine::Buffered,
+            CopyEngine::Std,
+            CopyEngine::IoUring,
+        ] {
+            let destination = dir.path().join(format!("{:?}.sh", engine));
+            assert_eq!(copy_file(&source, &destination, engine).unwrap(), 23, "{:?}", engine);
+            assert_eq!(fs::read(&destination).unwrap(), fs::read(&source).unwrap(), "{:?}", engine);
+            // A new file is never executable by default, only `--preserve mode` makes it so
+            let mode = fs::metadata(&destination).unwrap().permissions().mode();
+            assert_eq!(mode & 0o111, 0, "{:?} copied mode {:o}", engine, mode);
+        }
+    }
+}
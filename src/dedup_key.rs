@@ -0,0 +1,299 @@
+use std::fs;
+use std::io::{BufRead, Read};
+use std::path::Path;
+use glob::Pattern;
+use xxhash_rust::xxh3::Xxh3;
+use crate::args::{ContentKey, DedupRule};
+use crate::image_key::image_data_hash;
+use crate::similar_text::normalized_text_hash;
+use crate::throttle;
+use crate::yee_file::YeeFile;
+
+/// Extracts the key the `Store` uses to decide whether two files are duplicates.
+///
+/// Implementations look at whatever actually identifies a file of their type:
+/// the audio stream of an MP3 rather than its tags, the Message-ID of an email
+/// rather than its bytes. Returning `None` means the extractor doesn't apply to
+/// the file, and the whole-file hash is used instead.
+pub trait DedupKey {
+    /// Short name, used as the prefix of every key so different kinds never collide
+    fn name(&self) -> &'static str;
+
+    /// Compute the key for a file whose source is at `path`
+    fn key(&self, file: &YeeFile, path: &Path) -> anyhow::Result<Option<String>>;
+
+    /// Whether `key` uses the whole-file hash, which `Meta` otherwise only computes
+    /// when the key doesn't apply or something else needs it
+    fn needs_hash(&self) -> bool {
+        false
+    }
+}
+
+/// The whole-file content hash computed by `Meta`
+pub struct ContentHash;
+
+impl DedupKey for ContentHash {
+    fn name(&self) -> &'static str {
+        "hash"
+    }
+
+    fn key(&self, file: &YeeFile, _path: &Path) -> anyhow::Result<Option<String>> {
+        Ok(file.hash.clone())
+    }
+
+    fn needs_hash(&self) -> bool {
+        true
+    }
+}
+
+/// File name and size, for when reading every byte is too slow
+pub struct NameSize;
+
+impl DedupKey for NameSize {
+    fn name(&self) -> &'static str {
+        "name"
+    }
+
+    fn key(&self, file: &YeeFile, path: &Path) -> anyhow::Result<Option<String>> {
+        let size = fs::metadata(path)?.len();
+        Ok(Some(format!("{}.{}:{}", file.filename, file.extension, size)))
+    }
+}
+
+/// Audio data only, skipping ID3v1/ID3v2 tags and FLAC metadata blocks
+pub struct AudioStream;
+
+impl DedupKey for AudioStream {
+    fn name(&self) -> &'static str {
+        "audio"
+    }
+
+    fn key(&self, _file: &YeeFile, path: &Path) -> anyhow::Result<Option<String>> {
+        let data = throttle::read(path)?;
+        let mut start = 0;
+        let mut end = data.len();
+
+        if data.starts_with(b"fLaC") {
+            // Metadata blocks: 1 byte flags/type, 3 byte length; the high bit marks the last one
+            start = 4;
+            loop {
+                if start + 4 > end {
+                    anyhow::bail!("truncated FLAC metadata");
+                }
+                let header = &data[start..start + 4];
+                let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+                start += 4 + length;
+                if header[0] & 0x80 != 0 {
+                    break;
+                }
+            }
+        } else {
+            // ID3v2 tags may be stacked at the front, each with a syncsafe size
+            while data.len() >= start + 10 && &data[start..start + 3] == b"ID3" {
+                let size = data[start + 6..start + 10]
+                    .iter()
+                    .fold(0usize, |size, &b| (size << 7) | (b & 0x7F) as usize);
+                let footer = if data[start + 5] & 0x10 != 0 { 10 } else { 0 };
+                start += 10 + size + footer;
+            }
+            // ID3v1 is a fixed 128 byte trailer
+            if end >= start + 128 && &data[end - 128..end - 125] == b"TAG" {
+                end -= 128;
+            }
+        }
+
+        if start >= end {
+            return Ok(None);
+        }
+        let mut hasher = Xxh3::new();
+        hasher.update(&data[start..end]);
+        Ok(Some(format!("{:016x}", hasher.digest())))
+    }
+}
+
+/// Image data only, see `image_data_hash`
+pub struct ImageData;
+
+impl DedupKey for ImageData {
+    fn name(&self) -> &'static str {
+        "img"
+    }
+
+    fn key(&self, file: &YeeFile, path: &Path) -> anyhow::Result<Option<String>> {
+        image_data_hash(path, &file.extension)
+    }
+}
+
+/// Text with whitespace and line endings normalized, see `normalized_text_hash`
+pub struct NormalizedText;
+
+impl DedupKey for NormalizedText {
+    fn name(&self) -> &'static str {
+        "txt"
+    }
+
+    fn key(&self, _file: &YeeFile, path: &Path) -> anyhow::Result<Option<String>> {
+        normalized_text_hash(path)
+    }
+}
+
+/// The Message-ID header of an email, so the same message saved twice matches
+/// even when the headers added by different clients differ
+pub struct MessageId;
+
+impl DedupKey for MessageId {
+    fn name(&self) -> &'static str {
+        "msgid"
+    }
+
+    fn key(&self, _file: &YeeFile, path: &Path) -> anyhow::Result<Option<String>> {
+        // Headers end at the first blank line; don't read huge attachments
+        let reader = throttle::open(path)?.take(256 * 1024);
+        let mut message_id: Option<String> = None;
+        for line in reader.split(b'\n') {
+            let line = String::from_utf8_lossy(&line?).to_string();
+            if line.trim().is_empty() {
+                break;
+            }
+            // Folded header lines start with whitespace and continue the previous header
+            if line.starts_with([' ', '\t']) {
+                if let Some(id) = message_id.as_mut() {
+                    id.push_str(line.trim());
+                }
+                continue;
+            }
+            if message_id.is_some() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("message-id") {
+                    message_id = Some(value.trim().to_string());
+                }
+            }
+        }
+        Ok(message_id.filter(|id| !id.is_empty()))
+    }
+}
+
+/// Picks the `DedupKey` for each file: the first matching `--dedup-key` rule,
+/// otherwise the `--content-key` default
+pub struct DedupKeys {
+    default: Box<dyn DedupKey>,
+    rules: Vec<(Matcher, Box<dyn DedupKey>)>,
+}
+
+enum Matcher {
+    Extension(String),
+    Glob(Pattern),
+}
+
+impl DedupKeys {
+    pub fn new(default: ContentKey, rules: &[DedupRule]) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let matcher = match Pattern::new(&rule.pattern) {
+                    Ok(glob) if rule.pattern.contains(['*', '?', '[']) => Matcher::Glob(glob),
+                    _ => Matcher::Extension(rule.pattern.trim_start_matches('.').to_lowercase()),
+                };
+                (matcher, extractor(rule.key))
+            })
+            .collect();
+
+        Self {
+            default: extractor(default),
+            rules,
+        }
+    }
+
+    /// The extractor that applies to a file
+    pub fn for_file(&self, file: &YeeFile) -> &dyn DedupKey {
+        let file_name = format!("{}.{}", file.filename, file.extension);
+        self.rules
+            .iter()
+            .find(|(matcher, _)| match matcher {
+                Matcher::Extension(ext) => file.extension.eq_ignore_ascii_case(ext),
+                Matcher::Glob(glob) => glob.matches(&file_name),
+            })
+            .map_or(self.default.as_ref(), |(_, key)| key.as_ref())
+    }
+}
+
+fn extractor(key: ContentKey) -> Box<dyn DedupKey> {
+    match key {
+        ContentKey::Whole => Box::new(ContentHash),
+        ContentKey::NameSize => Box::new(NameSize),
+        ContentKey::AudioStream => Box::new(AudioStream),
+        ContentKey::ImageData => Box::new(ImageData),
+        ContentKey::Text => Box::new(NormalizedText),
+        ContentKey::MessageId => Box::new(MessageId),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// The audio key of a file with these bytes
+    fn audio_key(dir: &TempDir, name: &str, data: &[u8]) -> anyhow::Result<Option<String>> {
+        let path = dir.path().join(name);
+        fs::write(&path, data).unwrap();
+        AudioStream.key(&YeeFile::from_path(dir.path(), &path).unwrap(), &path)
+    }
+
+    /// An ID3v2 tag with a syncsafe size, and a footer if `footer`
+    fn id3v2(content: &[u8], footer: bool) -> Vec<u8> {
+        let size = content.len();
+        let syncsafe = [21, 14, 7, 0].map(|shift| (size >> shift) as u8 & 0x7F);
+        let mut tag = vec![b'I', b'D', b'3', 4, 0, if footer { 0x10 } else { 0 }];
+        tag.extend_from_slice(&syncsafe);
+        tag.extend_from_slice(content);
+        if footer {
+            tag.extend_from_slice(b"3DI\x04\x00\x10");
+            tag.extend_from_slice(&syncsafe);
+        }
+        tag
+    }
+
+    #[test]
+    fn audio_key_skips_id3_tags() {
+        let dir = TempDir::new().unwrap();
+        let audio = b"\xFF\xFBMPEG audio frames".repeat(20);
+        let plain = audio_key(&dir, "plain.mp3", &audio).unwrap();
+        assert!(plain.is_some());
+
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+        let mut tagged = [id3v2(&[b'T'; 200], false), id3v2(b"second tag", true), audio.clone()].concat();
+        tagged.extend_from_slice(&id3v1);
+        assert_eq!(audio_key(&dir, "tagged.mp3", &tagged).unwrap(), plain);
+
+        assert_ne!(audio_key(&dir, "other.mp3", &audio[1..]).unwrap(), plain);
+        // Nothing but tags has no audio to compare
+        assert_eq!(audio_key(&dir, "empty.mp3", &id3v2(b"tags only", false)).unwrap(), None);
+    }
+
+    /// A FLAC metadata block, the last one if `last`
+    fn flac_block(kind: u8, content: &[u8], last: bool) -> Vec<u8> {
+        let length = (content.len() as u32).to_be_bytes();
+        let mut block = vec![kind | if last { 0x80 } else { 0 }, length[1], length[2], length[3]];
+        block.extend_from_slice(content);
+        block
+    }
+
+    #[test]
+    fn audio_key_skips_flac_metadata() {
+        let dir = TempDir::new().unwrap();
+        let frames = b"\xFF\xF8FLAC frames".repeat(20);
+        let flac = |comment: &[u8]| {
+            [b"fLaC".to_vec(), flac_block(0, &[0; 34], false), flac_block(4, comment, true), frames.clone()].concat()
+        };
+        let key = audio_key(&dir, "a.flac", &flac(b"ARTIST=Someone")).unwrap();
+        assert!(key.is_some());
+        assert_eq!(audio_key(&dir, "b.flac", &flac(b"ARTIST=Someone else, retagged")).unwrap(), key);
+
+        let truncated = [b"fLaC".to_vec(), flac_block(0, &[0; 34], false)].concat();
+        assert!(audio_key(&dir, "c.flac", &truncated).is_err());
+    }
+}
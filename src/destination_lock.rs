@@ -0,0 +1,194 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::Context;
+use indicatif::HumanDuration;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use crate::args::LockPolicy;
+use crate::exit_code::LockedError;
+
+/// How often a waiting run checks the lock again
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Advisory lock on a destination, held by the run writing to it, so two runs don't
+/// interleave their numbering or overwrite each other's `.yeehaw` files.
+///
+/// The lock is an exclusive file lock (`flock` on Unix) on `<destination>/.yeehaw/lock`,
+/// which the OS releases however the run ends, so a run that crashed never leaves it
+/// behind. The file itself only names the holder, for the message of a run that finds
+/// the destination locked, and is removed when the lock is dropped.
+pub struct DestinationLock {
+    path: PathBuf,
+    /// The open lock file, holding the lock until it is closed
+    file: File,
+    /// Folders created for the lock file, removed with it if they are still empty
+    created: Vec<PathBuf>,
+}
+
+/// Who holds a lock, the contents of the lock file
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+struct Holder {
+    pid: u32,
+    host: String,
+    /// Milliseconds since the Unix epoch
+    started: u64,
+    /// Command line of the run
+    #[serde(default)]
+    command: String,
+}
+
+impl DestinationLock {
+    /// Take the lock on `destination_dir`. When another run holds it, `policy` decides:
+    /// wait for it, fail with a `LockedError`, or return `None` to carry on read-only.
+    pub fn acquire(destination_dir: &str, policy: LockPolicy) -> anyhow::Result<Option<Self>> {
+        let path = lock_path(destination_dir);
+        let folder = path.parent().unwrap_or(Path::new(destination_dir));
+        let created: Vec<PathBuf> = folder
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        fs::create_dir_all(folder).with_context(|| format!("Failed to create {}", folder.display()))?;
+
+        let mut waiting = false;
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            match file.try_lock() {
+                // The run before may have removed the file between it being opened and locked here
+                Ok(()) if !same_file(&file, &path) => continue,
+                Ok(()) => {
+                    let mut lock = Self { path, file, created };
+                    lock.write_holder()?;
+                    debug!("Locked {}", lock.path.display());
+                    return Ok(Some(lock));
+                }
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+                }
+            }
+
+            let held = format!("{} is locked by {}", destination_dir, describe_holder(&path));
+            match policy {
+                LockPolicy::Fail => return Err(LockedError(held).into()),
+                LockPolicy::ReadOnly => {
+                    warn!("{}, continuing read-only", held);
+                    return Ok(None);
+                }
+                LockPolicy::Wait => {
+                    if !waiting {
+                        info!("{}, waiting for it to finish", held);
+                        waiting = true;
+                    }
+                    drop(file);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Name this process in the lock file
+    fn write_holder(&mut self) -> anyhow::Result<()> {
+        let contents = serde_yaml::to_string(&Holder::current())?;
+        self.file.set_len(0)?;
+        self.file.write_all(contents.as_bytes())?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Drop for DestinationLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so a run waiting on this file sees it is gone and opens a new one
+        if !same_file(&self.file, &self.path) {
+            warn!("{} was replaced while this run held it, leaving it", self.path.display());
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove {}: {}", self.path.display(), e);
+            return;
+        }
+        for dir in &self.created {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+        debug!("Unlocked {}", self.path.display());
+    }
+}
+
+impl Holder {
+    /// This process
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            host: host_name(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let started = UNIX_EPOCH + Duration::from_millis(self.started);
+        let age = SystemTime::now().duration_since(started).unwrap_or_default();
+        format!("PID {} on {}, started {} ago", self.pid, self.host, HumanDuration(age))
+    }
+}
+
+/// The run holding the lock, as named in the lock file
+fn describe_holder(path: &Path) -> String {
+    match fs::read_to_string(path).map(|contents| serde_yaml::from_str::<Holder>(&contents)) {
+        Ok(Ok(holder)) => holder.describe(),
+        // It has the lock but hasn't written its name yet
+        Ok(Err(_)) => "another run".to_string(),
+        Err(e) => format!("another run (unreadable lock file: {})", e),
+    }
+}
+
+/// Where the lock of a destination is kept
+fn lock_path(destination_dir: &str) -> PathBuf {
+    Path::new(destination_dir).join(".yeehaw").join("lock")
+}
+
+/// Whether `path` still names the open `file`
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+        _ => false,
+    }
+}
+
+/// Files that are open can't be removed on other platforms, so the path is always the open file
+#[cfg(not(unix))]
+fn same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let end = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).to_string()
+}
+
+#[cfg(not(unix))]
+fn host_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+
+/// Every operation succeeded (or there was nothing to do)
+pub const SUCCESS: i32 = 0;
+/// The run stopped on an unexpected error, such as an unwritable journal
+pub const FATAL: i32 = 1;
+/// The command line or the configuration it points at is invalid; nothing was changed
+pub const CONFIG: i32 = 2;
+/// Some operations failed, the others succeeded
+pub const PARTIAL_FAILURE: i32 = 3;
+/// Every file operation that was attempted failed
+pub const TOTAL_FAILURE: i32 = 4;
+/// The preflight checks found the plan can't finish (no room, no permission); nothing was changed
+pub const PREFLIGHT: i32 = 5;
+/// Another run holds the destination lock (`--on-locked fail`); nothing was changed
+pub const LOCKED: i32 = 6;
+/// The run was stopped by SIGINT or SIGTERM (128 + SIGINT, like a shell)
+pub const INTERRUPTED: i32 = 130;
+
+/// An error in how yee-haw was invoked, as opposed to one hit while running
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The preflight checks failed, with this many problems (already logged)
+#[derive(Debug)]
+pub struct PreflightError(pub usize);
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Preflight checks found {} problem(s), nothing was changed", self.0)
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+/// Another run is writing to the destination
+#[derive(Debug)]
+pub struct LockedError(pub String);
+
+impl fmt::Display for LockedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LockedError {}
+
+/// Exit status for an error that ended the run
+pub fn for_error(error: &anyhow::Error) -> i32 {
+    if error.downcast_ref::<ConfigError>().is_some() {
+        CONFIG
+    } else if error.downcast_ref::<PreflightError>().is_some() {
+        PREFLIGHT
+    } else if error.downcast_ref::<LockedError>().is_some() {
+        LOCKED
+    } else {
+        FATAL
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::Path;
+use image::ImageReader;
+use xxhash_rust::xxh3::Xxh3;
+use crate::throttle;
+
+/// Hash only the image content of a file, ignoring any metadata it carries.
+///
+/// JPEG and PNG files are hashed from their compressed image data with the
+/// metadata segments (EXIF, XMP, comments, text chunks) skipped, so a retagged
+/// copy keeps the same key. Other formats are decoded and their pixels hashed.
+/// Returns `None` for files that aren't images.
+pub fn image_data_hash(path: &Path, extension: &str) -> anyhow::Result<Option<String>> {
+    let mut hasher = Xxh3::new();
+    let mut update = |bytes: &[u8]| hasher.update(bytes);
+    match extension.to_lowercase().as_str() {
+        "jpg" | "jpeg" => {
+            hash_jpeg(&throttle::read(path)?, &mut update)?;
+        }
+        "png" => {
+            hash_png(&throttle::read(path)?, &mut update)?;
+        }
+        "webp" | "gif" => {
+            let image = ImageReader::new(throttle::open(path)?).with_guessed_format()?.decode()?;
+            hasher.update(&image.width().to_le_bytes());
+            hasher.update(&image.height().to_le_bytes());
+            hasher.update(image.to_rgba8().as_raw());
+        }
+        _ => return Ok(None),
+    }
+    Ok(Some(format!("{:016x}", hasher.digest())))
+}
+
+/// Bytes of a JPEG or PNG file that `image_data_hash` skips as metadata.
+/// Returns `None` for other files.
+pub fn metadata_bytes(path: &Path, extension: &str) -> anyhow::Result<Option<u64>> {
+    let mut ignore = |_: &[u8]| {};
+    match extension.to_lowercase().as_str() {
+        "jpg" | "jpeg" => Ok(Some(hash_jpeg(&throttle::read(path)?, &mut ignore)?)),
+        "png" => Ok(Some(hash_png(&throttle::read(path)?, &mut ignore)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Feed every JPEG segment except EXIF/XMP (APP1), IPTC (APP13) and comments into
+/// `update`, followed by the entropy-coded scan data up to the last EOI marker.
+/// The ICC profile (APP2) and Adobe color transform (APP14) change how the pixels
+/// look, so they count as image data. Returns the size of the skipped segments.
+fn hash_jpeg(data: &[u8], update: &mut impl FnMut(&[u8])) -> anyhow::Result<u64> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        anyhow::bail!("missing JPEG SOI marker");
+    }
+
+    let mut skipped = 0;
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            anyhow::bail!("expected JPEG marker at offset {}", pos);
+        }
+        let marker = data[pos + 1];
+        // Fill bytes may pad between segments
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = (pos + 2 + length).min(data.len());
+
+        if marker == 0xDA {
+            // Start of scan: everything from here on is image data
+            let scan_end = data
+                .windows(2)
+                .rposition(|w| w == [0xFF, 0xD9])
+                .map_or(data.len(), |i| i + 2)
+                .max(end);
+            update(&data[pos..scan_end]);
+            return Ok(skipped);
+        }
+
+        match marker {
+            0xE1 | 0xED | 0xFE => skipped += (end - pos) as u64,
+            _ => update(&data[pos..end]),
+        }
+        pos = end;
+    }
+    anyhow::bail!("no JPEG scan data found")
+}
+
+/// Feed the critical PNG chunks (and transparency, which changes the pixels)
+/// into `update`, skipping text, EXIF, time and other ancillary chunks.
+/// Returns the size of the skipped chunks.
+fn hash_png(data: &[u8], update: &mut impl FnMut(&[u8])) -> anyhow::Result<u64> {
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    if !data.starts_with(&SIGNATURE) {
+        anyhow::bail!("missing PNG signature");
+    }
+
+    let mut skipped = 0;
+    let mut pos = SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        // Length, type, data and CRC
+        let end = (pos + 12 + length).min(data.len());
+
+        let critical = kind[0].is_ascii_uppercase();
+        match critical || kind == b"tRNS" {
+            true => update(&data[pos + 4..end]),
+            false => skipped += (end - pos) as u64,
+        }
+        if kind == b"IEND" {
+            return Ok(skipped);
+        }
+        pos = end;
+    }
+    anyhow::bail!("PNG ended without an IEND chunk")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JPEG marker segment with its length
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, marker];
+        bytes.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn jpeg(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8];
+        bytes.extend(segments.concat());
+        bytes.extend(segment(0xDA, b"scan header"));
+        bytes.extend_from_slice(b"entropy coded data\xFF\xD9");
+        bytes
+    }
+
+    /// The bytes of a JPEG fed to the hasher
+    fn jpeg_data(data: &[u8]) -> Vec<u8> {
+        let mut fed = Vec::new();
+        hash_jpeg(data, &mut |bytes: &[u8]| fed.extend_from_slice(bytes)).unwrap();
+        fed
+    }
+
+    /// The bytes of a PNG fed to the hasher
+    fn png_data(data: &[u8]) -> Vec<u8> {
+        let mut fed = Vec::new();
+        hash_png(data, &mut |bytes: &[u8]| fed.extend_from_slice(bytes)).unwrap();
+        fed
+    }
+
+    #[test]
+    fn jpeg_skips_exif_iptc_and_comments_only() {
+        let tables = segment(0xDB, b"quantization");
+        let plain = jpeg(std::slice::from_ref(&tables));
+        let tagged = jpeg(&[
+            segment(0xE1, b"Exif\0\0tags"),
+            tables.clone(),
+            segment(0xED, b"Photoshop 3.0 IPTC"),
+            segment(0xFE, b"a comment"),
+        ]);
+        assert_eq!(jpeg_data(&plain), jpeg_data(&tagged));
+
+        let with_icc = jpeg(&[segment(0xE2, b"ICC_PROFILE"), tables.clone()]);
+        let with_adobe = jpeg(&[tables.clone(), segment(0xEE, b"Adobe")]);
+        assert_ne!(jpeg_data(&plain), jpeg_data(&with_icc));
+        assert_ne!(jpeg_data(&plain), jpeg_data(&with_adobe));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        std::fs::write(&path, &tagged).unwrap();
+        assert_eq!(metadata_bytes(&path, "JPG").unwrap(), Some((tagged.len() - plain.len()) as u64));
+        assert!(hash_jpeg(b"not a jpeg", &mut |_: &[u8]| {}).is_err());
+    }
+
+    fn chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(kind);
+        bytes.extend_from_slice(payload);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes
+    }
+
+    #[test]
+    fn png_skips_ancillary_chunks_but_transparency() {
+        let png = |chunks: &[Vec<u8>]| {
+            let mut bytes = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+            bytes.extend(chunk(b"IHDR", b"header"));
+            bytes.extend(chunks.concat());
+            bytes.extend(chunk(b"IDAT", b"pixels"));
+            bytes.extend(chunk(b"IEND", b""));
+            bytes
+        };
+        let plain = png(&[]);
+        let tagged = png(&[chunk(b"tEXt", b"Comment\0hi"), chunk(b"tIME", b"1234567")]);
+        assert_eq!(png_data(&plain), png_data(&tagged));
+        assert_eq!(hash_png(&tagged, &mut |_: &[u8]| {}).unwrap(), (tagged.len() - plain.len()) as u64);
+        assert_ne!(png_data(&plain), png_data(&png(&[chunk(b"tRNS", b"\0")])));
+
+        let mut truncated = plain.clone();
+        truncated.truncate(plain.len() - 12);
+        assert!(hash_png(&truncated, &mut |_: &[u8]| {}).is_err());
+    }
+}
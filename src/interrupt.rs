@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use crate::exit_code;
+
+/// Set by the first SIGINT or SIGTERM
+static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Catch SIGINT and SIGTERM so the executor can stop between operations instead of
+/// dying in the middle of one. A second signal exits immediately.
+pub fn install() -> anyhow::Result<()> {
+    let interrupted = INTERRUPTED.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first, so it only fires once the flag is already set
+        flag::register_conditional_shutdown(signal, exit_code::INTERRUPTED, Arc::clone(interrupted))?;
+        flag::register(signal, Arc::clone(interrupted))?;
+    }
+    Ok(())
+}
+
+/// Whether a stop was requested since `install`
+pub fn requested() -> bool {
+    INTERRUPTED.get().is_some_and(|interrupted| interrupted.load(Ordering::Relaxed))
+}
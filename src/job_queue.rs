@@ -0,0 +1,144 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// Hands the copies and moves of a plan to the copy workers in order, with at most
+/// `device_limit` of them reading from or writing to the same device at once.
+///
+/// A job whose device is busy doesn't hold up the ones behind it that use other devices.
+/// Batchable jobs next to each other in the queue can be handed out together, sharing
+/// one slot per device.
+pub struct JobQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    device_limit: usize,
+}
+
+struct QueueState {
+    pending: VecDeque<Job>,
+    /// Jobs running per device
+    busy: HashMap<u64, usize>,
+    stopped: bool,
+}
+
+/// A plan operation waiting for a worker
+pub struct Job {
+    /// Index of the operation in the plan
+    pub index: usize,
+    /// Devices the operation reads from and writes to
+    devices: Vec<u64>,
+    /// Whether the operation can run in a batch with others on the same devices
+    batchable: bool,
+}
+
+/// Jobs handed to a worker at once. Frees its device slots when dropped, and stops the
+/// queue if that happens because the worker panicked, so the other workers don't wait
+/// on slots forever.
+pub struct Batch<'a> {
+    /// Indices of the operations in the plan
+    pub indices: Vec<usize>,
+    devices: Vec<u64>,
+    queue: &'a JobQueue,
+}
+
+impl Job {
+    pub fn new(index: usize, mut devices: Vec<u64>, batchable: bool) -> Self {
+        devices.sort_unstable();
+        devices.dedup();
+        Self { index, devices, batchable }
+    }
+}
+
+impl JobQueue {
+    pub fn new(jobs: Vec<Job>, device_limit: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState { pending: jobs.into(), busy: HashMap::new(), stopped: false }),
+            changed: Condvar::new(),
+            device_limit: device_limit.max(1),
+        }
+    }
+
+    /// The next job whose devices have a free slot, waiting for one if they are all busy,
+    /// with up to `max - 1` batchable jobs on the same devices queued right behind it.
+    /// `None` once the queue is empty or stopped.
+    pub fn next(&self, max: usize) -> Option<Batch<'_>> {
+        let mut state = self.lock();
+        loop {
+            if state.stopped || state.pending.is_empty() {
+                return None;
+            }
+            let free = |device: &u64| state.busy.get(device).copied().unwrap_or(0) < self.device_limit;
+            if let Some(position) = state.pending.iter().position(|job| job.devices.iter().all(free)) {
+                let job = state.pending.remove(position)?;
+                for device in &job.devices {
+                    *state.busy.entry(*device).or_default() += 1;
+                }
+                let mut indices = vec![job.index];
+                let same = |other: &Job| job.batchable && other.batchable && other.devices == job.devices;
+                while indices.len() < max && state.pending.get(position).is_some_and(same) {
+                    indices.extend(state.pending.remove(position).map(|other| other.index));
+                }
+                return Some(Batch { indices, devices: job.devices, queue: self });
+            }
+            state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Free the device slots of a batch returned by `next`
+    fn release(&self, batch: &Batch) {
+        let mut state = self.lock();
+        for device in &batch.devices {
+            if let Some(busy) = state.busy.get_mut(device) {
+                *busy = busy.saturating_sub(1);
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// Hand out no more jobs; the ones already running finish
+    pub fn stop(&self) {
+        self.lock().stopped = true;
+        self.changed.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.queue.stop();
+        }
+        self.queue.release(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropping_a_batch_frees_its_device() {
+        let queue = JobQueue::new(vec![Job::new(0, vec![1], false), Job::new(1, vec![1], false)], 1);
+        let first = queue.next(1).unwrap();
+        assert_eq!(first.indices, vec![0]);
+        drop(first);
+        assert_eq!(queue.next(1).unwrap().indices, vec![1]);
+    }
+
+    #[test]
+    fn panicking_worker_stops_the_queue() {
+        let queue = JobQueue::new(vec![Job::new(0, vec![1], false), Job::new(1, vec![1], false)], 1);
+        thread::scope(|scope| {
+            let worker = scope.spawn(|| {
+                let _batch = queue.next(1);
+                panic!("copy failed");
+            });
+            assert!(worker.join().is_err());
+        });
+        // Neither waits for the slot nor hands out more work
+        assert!(queue.next(1).is_none());
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Context;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tiny_id::ShortCodeGenerator;
+use crate::meta::hash_file;
+use crate::mover::{remove_source, temp_path};
+use crate::exit_code::ConfigError;
+use crate::plan::Plan;
+
+/// Append-only record of everything a run changed on disk, kept under
+/// `<destination>/.yeehaw/journal/<run-id>.jsonl`.
+///
+/// Each operation is written once before it starts and once when it is done (or
+/// failed), one JSON object per line, and synced to disk every time. `yee-haw undo`
+/// replays it in reverse, and `yee-haw resume` uses it to skip what is already done.
+pub struct Journal {
+    run_id: String,
+    path: PathBuf,
+    file: File,
+    seq: usize,
+    /// Plan operations finished by an earlier, interrupted attempt at this run
+    done: HashSet<usize>,
+    /// Plan operations whose copy over an existing file was interrupted, possibly
+    /// leaving it half written
+    overwriting: HashSet<usize>,
+    /// Where the copies and moves finished by earlier attempts put their file
+    written: HashMap<usize, String>,
+}
+
+/// One line of the journal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub run_id: String,
+    /// Number of the operation; its begin and done entries share it
+    pub seq: usize,
+    /// Index of the plan operation this is part of
+    pub op: usize,
+    pub phase: Phase,
+    pub action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub destination: String,
+    /// Hash of the data written to the destination
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Whether the operation replaced a file that was already at the destination
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replaced: bool,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Begin,
+    Done,
+    Failed,
+}
+
+/// What was done to the destination (and the source)
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Created a directory
+    Mkdir,
+    /// Wrote a metadata file
+    WriteMetadata,
+    /// Copied the source to the destination
+    Copy,
+    /// Moved the source to the destination
+    Move,
+    /// Hardlinked the destination to another destination, removing the source if set
+    Link,
+    /// Removed a source already present at the destination
+    RemoveSource,
+}
+
+impl Action {
+    /// Name as it appears in the journal and in `--output` records
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Mkdir => "mkdir",
+            Action::WriteMetadata => "write-metadata",
+            Action::Copy => "copy",
+            Action::Move => "move",
+            Action::Link => "link",
+            Action::RemoveSource => "remove-source",
+        }
+    }
+}
+
+impl Journal {
+    /// Start the journal of a new run under the destination directory
+    pub fn create(destination_dir: &str) -> anyhow::Result<Self> {
+        let run_id = new_run_id();
+        let path = journal_path(destination_dir, &run_id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create journal {}", path.display()))?;
+        debug!("Journaling run {} to {}", run_id, path.display());
+
+        Ok(Self {
+            run_id,
+            path,
+            file,
+            seq: 0,
+            done: HashSet::new(),
+            overwriting: HashSet::new(),
+            written: HashMap::new(),
+        })
+    }
+
+    /// Reopen the journal of an interrupted run to carry on with it.
+    ///
+    /// Operations that were interrupted are checked on disk: if the file landed with
+    /// the right hash they are marked done (a move whose source is still there is
+    /// finished by removing it), otherwise whatever they left behind is removed and
+    /// they are marked failed so they run again. A copy that was overwriting a file
+    /// is redone over it, see `was_overwriting`.
+    pub fn open(destination_dir: &str, run_id: &str) -> anyhow::Result<Self> {
+        let path = journal_path(destination_dir, run_id);
+        if !path.exists() {
+            return Err(ConfigError(format!("No journal for run {} in {}", run_id, destination_dir)).into());
+        }
+        let entries = read(&path)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open journal {}", path.display()))?;
+        let seq = entries.iter().map(|entry| entry.seq).max().unwrap_or(0);
+        let mut journal = Self {
+            run_id: run_id.to_string(),
+            path,
+            file,
+            seq,
+            done: HashSet::new(),
+            overwriting: HashSet::new(),
+            written: HashMap::new(),
+        };
+
+        // The last entry of each operation says how far it got, the last operation of
+        // each plan step says whether the step finished
+        let mut operations: BTreeMap<usize, JournalEntry> = BTreeMap::new();
+        for entry in entries {
+            operations.insert(entry.seq, entry);
+        }
+        let mut steps: BTreeMap<usize, bool> = BTreeMap::new();
+        for entry in operations.into_values() {
+            let finished = match entry.phase {
+                Phase::Done => true,
+                Phase::Failed => false,
+                // Metadata is cheap and may be cut short, so it is always rewritten
+                Phase::Begin if entry.action != Action::WriteMetadata && entry.looks_done() => {
+                    debug!("Interrupted {:?} {} had completed", entry.action, entry.destination);
+                    journal.done(entry.clone())?;
+                    true
+                }
+                // The data landed but the source wasn't removed yet, which is all that is left to do
+                Phase::Begin if entry.action == Action::Move && entry.landed() => {
+                    debug!("Interrupted move to {} had landed, removing its source", entry.destination);
+                    if let Err(e) = remove_source(entry.source.as_deref().unwrap_or_default()) {
+                        warn!("{:#}", e);
+                    }
+                    journal.done(entry.clone())?;
+                    true
+                }
+                Phase::Begin => {
+                    entry.discard_partial();
+                    if entry.replaced && entry.action == Action::Copy {
+                        warn!("{} may be partly overwritten, it will be copied over again", entry.destination);
+                        journal.overwriting.insert(entry.op);
+                    }
+                    journal.failed(entry.clone())?;
+                    false
+                }
+            };
+            if finished && matches!(entry.action, Action::Copy | Action::Move | Action::RemoveSource) {
+                journal.written.insert(entry.op, entry.destination.clone());
+            }
+            steps.insert(entry.op, finished);
+        }
+        journal.done = steps.into_iter().filter(|(_, finished)| *finished).map(|(op, _)| op).collect();
+
+        Ok(journal)
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Save the plan next to the journal before running it, so `resume` runs the same plan
+    pub fn checkpoint(&self, plan: &Plan) -> anyhow::Result<()> {
+        plan.write(&self.path.with_extension("plan.yaml").to_string_lossy())
+    }
+
+    /// The plan saved by `checkpoint` when the run started
+    pub fn checkpointed_plan(&self) -> anyhow::Result<Plan> {
+        let path = self.path.with_extension("plan.yaml");
+        Plan::read(&path)
+            .with_context(|| format!("Failed to read the plan of run {} from {}", self.run_id, path.display()))
+    }
+
+    /// Whether plan operation `op` was finished by an earlier attempt at this run
+    pub fn is_done(&self, op: usize) -> bool {
+        self.done.contains(&op)
+    }
+
+    /// Where an earlier attempt at this run wrote the file of plan operation `op`, if it did
+    pub fn written_to(&self, op: usize) -> Option<&str> {
+        self.written.get(&op).map(String::as_str)
+    }
+
+    /// Whether an earlier attempt at this run stopped while copying plan operation `op`
+    /// over an existing file. Whatever `--on-conflict` says, it is copied over it again,
+    /// as the file may be half written.
+    pub fn was_overwriting(&self, op: usize) -> bool {
+        self.overwriting.contains(&op)
+    }
+
+    /// Number of plan operations finished by earlier attempts
+    pub fn done_count(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Record that an operation is about to happen, returning the entry to complete later
+    pub fn begin(
+        &mut self,
+        op: usize,
+        action: Action,
+        source: Option<&str>,
+        destination: &str,
+        hash: Option<&str>,
+    ) -> anyhow::Result<JournalEntry> {
+        self.seq += 1;
+        let entry = JournalEntry {
+            run_id: self.run_id.clone(),
+            seq: self.seq,
+            op,
+            phase: Phase::Begin,
+            action,
+            source: source.map(absolute),
+            destination: absolute(destination),
+            hash: hash.map(str::to_string),
+            replaced: action != Action::RemoveSource && fs::symlink_metadata(destination).is_ok(),
+            timestamp: 0,
+        };
+        self.append(entry.clone())?;
+        Ok(entry)
+    }
+
+    /// Record that an operation started with `begin` has finished
+    pub fn done(&mut self, entry: JournalEntry) -> anyhow::Result<()> {
+        self.append(JournalEntry { phase: Phase::Done, ..entry })
+    }
+
+    /// Record that an operation started with `begin` did not happen
+    pub fn failed(&mut self, entry: JournalEntry) -> anyhow::Result<()> {
+        self.append(JournalEntry { phase: Phase::Failed, ..entry })
+    }
+
+    fn append(&mut self, mut entry: JournalEntry) -> anyhow::Result<()> {
+        entry.timestamp = now_millis();
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+impl JournalEntry {
+    /// Whether an operation that was interrupted before being marked done actually happened
+    pub fn looks_done(&self) -> bool {
+        let destination = Path::new(&self.destination);
+        let source_gone = self.source.as_ref().is_none_or(|source| !Path::new(source).exists());
+        match self.action {
+            Action::Mkdir | Action::WriteMetadata | Action::Link => destination.exists(),
+            Action::Copy => self.landed(),
+            Action::Move => self.landed() && source_gone,
+            Action::RemoveSource => source_gone,
+        }
+    }
+
+    /// Whether a copy's or move's data is in place at the destination, whether or not a
+    /// move's source was removed. Without a hash, a destination that was there before
+    /// may be the old file or half overwritten, so it never counts.
+    fn landed(&self) -> bool {
+        (self.hash.is_some() || !self.replaced) && self.destination_matches()
+    }
+
+    /// Whether the destination still has the hash recorded in the journal. Without one,
+    /// it has to have the same size and contents as the source, if that is still there.
+    pub fn destination_matches(&self) -> bool {
+        let destination = Path::new(&self.destination);
+        match (&self.hash, self.source.as_deref().map(Path::new)) {
+            (Some(hash), _) => hash_file(destination).is_ok_and(|current| &current == hash),
+            (None, Some(source)) if source.exists() => same_contents(source, destination),
+            (None, _) => destination.exists(),
+        }
+    }
+
+    /// Remove what an interrupted operation left behind: the temp file of a move across
+    /// filesystems, or a partial copy at a destination that was free before it
+    fn discard_partial(&self) {
+        let destination = Path::new(&self.destination);
+        if self.action == Action::Move {
+            let temp = temp_path(destination);
+            match fs::remove_file(&temp) {
+                Ok(()) => debug!("Removed temp file {}", temp.display()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to remove temp file {}: {}", temp.display(), e),
+            }
+        }
+        if self.replaced || !matches!(self.action, Action::Copy | Action::WriteMetadata) {
+            return;
+        }
+        if destination.exists() {
+            match fs::remove_file(&self.destination) {
+                Ok(()) => debug!("Removed partial file {}", self.destination),
+                Err(e) => warn!("Failed to remove partial file {}: {}", self.destination, e),
+            }
+        }
+    }
+}
+
+/// Whether two files have the same size and, only then, the same hash
+fn same_contents(a: &Path, b: &Path) -> bool {
+    let size = |path: &Path| fs::metadata(path).map(|metadata| metadata.len()).ok();
+    size(a).is_some()
+        && size(a) == size(b)
+        && matches!((hash_file(a), hash_file(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Where the journal of a run is kept
+pub fn journal_path(destination_dir: &str, run_id: &str) -> PathBuf {
+    Path::new(destination_dir)
+        .join(".yeehaw")
+        .join("journal")
+        .join(format!("{}.jsonl", run_id))
+}
+
+/// Read every entry of a journal. A line cut short by a crash is skipped.
+pub fn read(path: &Path) -> anyhow::Result<Vec<JournalEntry>> {
+    let file = File::open(path).with_context(|| format!("Failed to open journal {}", path.display()))?;
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!("Skipping unreadable line {} of {}: {}", number + 1, path.display(), e),
+        }
+    }
+    Ok(entries)
+}
+
+/// A run id that sorts by start time: `20250101-120000-ab12cd`
+fn new_run_id() -> String {
+    let seconds = now_millis() / 1000;
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    let suffix = ShortCodeGenerator::new_lowercase_alphanumeric(6).next_string();
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        suffix
+    )
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) UTC date
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Journal paths are absolute so a run can be undone from any working directory
+fn absolute(path: &str) -> String {
+    std::path::absolute(path)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A source file next to an empty destination directory, with the path it is copied to
+    fn setup() -> (TempDir, String, String, String) {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("photo.jpg");
+        fs::write(&source, b"the whole file, not just a part of it").unwrap();
+        let destination_dir = dir.path().join("organized");
+        fs::create_dir(&destination_dir).unwrap();
+        let destination = destination_dir.join("photo.jpg");
+        let path = |path: &Path| path.to_string_lossy().to_string();
+        (dir, path(&source), path(&destination_dir), path(&destination))
+    }
+
+    /// Journal a copy as begun, leave `content` at its destination and reopen the journal
+    /// as `resume` does
+    fn interrupt_copy(hash: bool, content: &[u8]) -> (TempDir, Journal, String) {
+        let (dir, source, destination_dir, destination) = setup();
+        let hash = hash.then(|| hash_file(Path::new(&source)).unwrap());
+        let mut journal = Journal::create(&destination_dir).unwrap();
+        journal.begin(0, Action::Copy, Some(&source), &destination, hash.as_deref()).unwrap();
+        fs::write(&destination, content).unwrap();
+
+        let journal = Journal::open(&destination_dir, journal.run_id()).unwrap();
+        (dir, journal, destination)
+    }
+
+    #[test]
+    fn resume_discards_partial_copy() {
+        let (_dir, journal, destination) = interrupt_copy(true, b"the whole file");
+        assert!(!journal.is_done(0));
+        assert!(!Path::new(&destination).exists());
+    }
+
+    #[test]
+    fn resume_discards_partial_copy_without_hash() {
+        let (_dir, journal, destination) = interrupt_copy(false, b"the whole file");
+        assert!(!journal.is_done(0));
+        assert!(!Path::new(&destination).exists());
+    }
+
+    #[test]
+    fn resume_keeps_finished_copy_without_hash() {
+        let (_dir, journal, destination) = interrupt_copy(false, b"the whole file, not just a part of it");
+        assert!(journal.is_done(0));
+        assert_eq!(journal.written_to(0), Some(destination.as_str()));
+    }
+
+    #[test]
+    fn resume_redoes_overwrite_without_hash() {
+        let (_dir, source, destination_dir, destination) = setup();
+        fs::write(&destination, b"an older file").unwrap();
+        let mut journal = Journal::create(&destination_dir).unwrap();
+        journal.begin(0, Action::Copy, Some(&source), &destination, None).unwrap();
+        fs::copy(&source, &destination).unwrap();
+
+        // Even a complete looking copy may be the old file, so it runs again over it
+        let journal = Journal::open(&destination_dir, journal.run_id()).unwrap();
+        assert!(!journal.is_done(0));
+        assert!(journal.was_overwriting(0));
+        assert!(Path::new(&destination).exists());
+    }
+
+    #[test]
+    fn resume_finishes_landed_move_by_removing_source() {
+        let (_dir, source, destination_dir, destination) = setup();
+        let hash = hash_file(Path::new(&source)).unwrap();
+        let mut journal = Journal::create(&destination_dir).unwrap();
+        journal.begin(0, Action::Move, Some(&source), &destination, Some(&hash)).unwrap();
+        fs::copy(&source, &destination).unwrap();
+
+        let journal = Journal::open(&destination_dir, journal.run_id()).unwrap();
+        assert!(journal.is_done(0));
+        assert_eq!(journal.written_to(0), Some(destination.as_str()));
+        assert!(!Path::new(&source).exists());
+    }
+
+    #[test]
+    fn resume_removes_temp_file_of_interrupted_move() {
+        let (_dir, source, destination_dir, destination) = setup();
+        let hash = hash_file(Path::new(&source)).unwrap();
+        let mut journal = Journal::create(&destination_dir).unwrap();
+        journal.begin(0, Action::Move, Some(&source), &destination, Some(&hash)).unwrap();
+        let temp = temp_path(Path::new(&destination));
+        fs::write(&temp, b"the whole").unwrap();
+
+        let journal = Journal::open(&destination_dir, journal.run_id()).unwrap();
+        assert!(!journal.is_done(0));
+        assert_eq!(journal.written_to(0), None);
+        assert!(!temp.exists());
+        assert!(Path::new(&source).exists());
+    }
+}
@@ -4,24 +4,107 @@ mod mover;
 mod yee_file;
 mod meta;
 mod store;
+mod tui;
+mod tree;
+mod report;
+mod similar_images;
+mod image_key;
+mod similar_text;
+mod dedup_key;
+mod plan;
+mod preserve;
+mod journal;
+mod undo;
+mod interrupt;
+mod exit_code;
+mod output;
+mod progress;
+mod job_queue;
+mod copy;
+mod throttle;
+mod preflight;
+mod destination_lock;
 
 use scanner::Scanner;
 use mover::Mover;
 use meta::Meta;
 use store::Store;
-use args::YeeArgs;
-use log::{info, debug, error};
+use args::{YeeArgs, EmptyFiles, Command, CopyEngine, LockPolicy};
+use tui::Review;
+use tree::DirTree;
+use report::{DupesReport, RunReport};
+use similar_images::SimilarImages;
+use similar_text::SimilarText;
+use plan::Planner;
+use journal::Journal;
+use undo::Undo;
+use exit_code::ConfigError;
+use preflight::Preflight;
+use destination_lock::DestinationLock;
+use output::{ErrorRecord, Output};
+use std::path::Path;
+use anyhow::Context;
+use log::{info, warn, error};
 
 /// Main entry point for the Yee-Haw file organization tool
-fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init();
+fn main() {
+    progress::init_logger();
     
     // Parse command line arguments
     let args = YeeArgs::parse_args();
+    progress::configure(args.quiet);
+    throttle::configure(args.bwlimit, args.ops_limit);
+    if args.nice {
+        if let Err(e) = throttle::lower_priority() {
+            warn!("Failed to lower the I/O and CPU priority: {}", e);
+        }
+    }
+    
+    // The exit status tells scripts how the run went, see `exit_code`
+    let mut output = Output::new(args.output);
+    let (code, error) = match run(args, &mut output) {
+        Ok(code) => (code, None),
+        Err(e) => {
+            error!("{:#}", e);
+            let exit_code = exit_code::for_error(&e);
+            (exit_code, Some(ErrorRecord { message: format!("{:#}", e), exit_code }))
+        }
+    };
+    output.close(error);
+    std::process::exit(code);
+}
+
+/// Run the command given on the command line, returning the exit status
+fn run(mut args: YeeArgs, output: &mut Output) -> anyhow::Result<i32> {
+    match &args.command {
+        Some(Command::Undo { run_id }) => {
+            let _lock = lock_destination(&args, false)?;
+            return Undo::new(args.clone()).run(run_id);
+        }
+        Some(Command::Resume { run_id }) => {
+            let _lock = lock_destination(&args, false)?;
+            return resume(&args, run_id, output);
+        }
+        None => {}
+    }
+    
+    validate(&args)?;
+    
+    // One run at a time writes to a destination, a dry run only reads it
+    let _lock = match args.dry {
+        true => None,
+        false => {
+            let lock = lock_destination(&args, true)?;
+            // Another run holds the lock, this one only shows its plan
+            args.dry = lock.is_none();
+            lock
+        }
+    };
     
     // Create components
     let scanner = Scanner::new();
     let mut meta = Meta::new(args.clone());
+    let planner = Planner::new(args.clone());
     let mover = Mover::new(args.clone());
     
     // === Step 1: Scan for files ===
@@ -31,21 +114,70 @@ fn main() -> anyhow::Result<()> {
     
     if files.is_empty() {
         info!("No files found. Exiting.");
-        return Ok(());
+        return Ok(exit_code::SUCCESS);
     }
     
     // === Step 2: Process metadata (calculate hashes and set destination paths) ===
     info!("Processing file metadata and creating destination paths...");
-    if let Err(e) = meta.process(&mut files) {
-        error!("Error processing metadata: {}", e);
-    }
+    meta.process(&mut files).context("Error processing metadata")?;
     
     // === Step 3: Handle duplicates if tracking is enabled ===
-    let mut store = Store::new();
+    let mut store = Store::new(args.min_dedup_size);
+    let mut report = DupesReport::default();
+    
+    // Empty files all hash the same, so they get their own policy instead of deduping
+    let empty_count = files.iter().filter(|f| f.size == 0).count();
+    if empty_count > 0 {
+        match args.empty_files {
+            EmptyFiles::Ignore => {
+                info!("Leaving {} empty files in place", empty_count);
+                files.retain(|f| f.size > 0);
+            },
+            EmptyFiles::KeepAll => {
+                info!("Keeping all {} empty files", empty_count);
+            },
+            EmptyFiles::Report => {
+                info!("Keeping all {} empty files and adding them to the report", empty_count);
+                report.add_empty_files(&files);
+            },
+        }
+    }
+    
+    // Directory analysis needs every file, so run it before the store takes them
+    if args.dir_dupes || args.similar_dirs.is_some() {
+        info!("Analyzing directory trees for duplicate folders...");
+        let dir_tree = DirTree::build(&args.source_dir, &files);
+        if args.dir_dupes {
+            report.add_dir_tree(&dir_tree);
+        }
+        if let Some(threshold) = args.similar_dirs {
+            report.add_similar_dirs(&dir_tree, threshold);
+        }
+    }
     
+    // === Step 4: Plan every operation, nothing on disk changes until it runs ===
     if args.track_duplicates {
         info!("Checking for duplicate files...");
         store.insert_batch(files);
+        store.apply_keep_policy(args.keep_policy);
+        
+        // Near-duplicate images only differ from the kept copy visually, not byte for byte
+        if args.similar_images {
+            info!("Checking for visually similar images...");
+            let clusters = SimilarImages::new(args.image_hash, args.image_distance, args.min_dedup_size)
+                .find(store.originals());
+            let demotions: Vec<(usize, usize)> = clusters
+                .iter()
+                .flat_map(|cluster| {
+                    let kept = cluster.members[cluster.kept].index;
+                    cluster.members.iter().map(move |m| (m.index, kept))
+                })
+                .filter(|(index, kept)| index != kept)
+                .collect();
+            store.demote(&demotions);
+            report.add_similar_images(&clusters);
+        }
+        report.add_file_clusters(&store);
         
         info!(
             "Found {} original files and {} duplicates", 
@@ -53,105 +185,146 @@ fn main() -> anyhow::Result<()> {
             store.duplicate_count()
         );
         
-        if args.dry {
-            // In dry run mode, just show what would happen
-            let action = if args.copy_mode { "copy" } else { "move" };
-            info!(
-                "DRY RUN: Would {} {} original files to their destination folders", 
-                action,
-                store.original_count()
-            );
-            
-            if store.duplicate_count() > 0 {
-                info!(
-                    "DRY RUN: Would {} {} duplicate files to the _dupes directory", 
-                    action,
-                    store.duplicate_count()
-                );
-            }
-            
-            debug!("Renaming style: {:?}, Group style: {:?}", 
-                args.rename_style, args.group_style);
-            
-            // Display sample destination paths for a few files
-            let sample_size = std::cmp::min(3, store.original_count());
-            if sample_size > 0 {
-                debug!("Sample destination paths:");
-                for (i, file) in store.originals().iter().take(sample_size).enumerate() {
-                    debug!(
-                        "  {}: {}/{}.{}", 
-                        i+1,
-                        file.destination_full_path,
-                        file.filename,
-                        file.extension
-                    );
-                }
-            }
-            
-            debug!("Duplicate files that would be skipped:");
-            for file in store.duplicates() {
-                debug!(
-                    "  {}.{} (hash: {})", 
-                    file.filename,
-                    file.extension,
-                    file.hash.as_deref().unwrap_or("none")
-                );
-            }
-        } else {
-            // Actually move/copy the files
-            let action = if args.copy_mode { "Copying" } else { "Moving" };
-            info!(
-                "{} {} original files to their destination folders", 
-                action,
-                store.original_count()
-            );
-            
-            // Destination paths are already set by the meta processor
-            mover.move_files(store.originals().to_vec())?;
-            
-            // Move/copy duplicates to the _dupes directory
-            if store.duplicate_count() > 0 {
-                mover.move_duplicates(store.duplicates().to_vec())?;
-            }
+        // Near-identical documents are only reported, revisions shouldn't be moved aside
+        if let Some(threshold) = args.similar_text {
+            info!("Checking for similar text files...");
+            report.add_similar_text(&SimilarText::new(threshold).find(store.originals()));
         }
     } else {
         // No duplicate tracking, just move/copy all files
         info!("Duplicate tracking disabled");
-        
-        if args.dry {
-            let action = if args.copy_mode { "copy" } else { "move" };
-            info!(
-                "DRY RUN: Would {} {} files to their destination folders", 
-                action,
-                files.len()
-            );
-            
-            debug!("Renaming style: {:?}, Group style: {:?}", 
-                args.rename_style, args.group_style);
-                
-            // Display sample destination paths for a few files
-            let sample_size = std::cmp::min(3, files.len());
-            if sample_size > 0 {
-                debug!("Sample destination paths:");
-                for (i, file) in files.iter().take(sample_size).enumerate() {
-                    debug!(
-                        "  {}: {}/{}.{}", 
-                        i+1,
-                        file.destination_full_path,
-                        file.filename,
-                        file.extension
-                    );
-                }
-            }
-        } else {
-            let action = if args.copy_mode { "Copying" } else { "Moving" };
-            info!("{} {} files to their destination folders", action, files.len());
-            
-            // Destination paths are already set by the meta processor
-            mover.move_files(files)?;
+        store.keep_all(files);
+    }
+    
+    finish_report(&args, &report)?;
+    
+    // Let the user walk through the clusters and the plan, and confirm before anything moves
+    if args.review && !Review::new(&mut store, &planner).run()? {
+        info!("Review aborted, no files were moved.");
+        return Ok(exit_code::SUCCESS);
+    }
+    
+    let plan = planner.build(store.originals(), &store.duplicate_pairs())?;
+    
+    if let Some(path) = &args.plan_out {
+        plan.write(path)?;
+    }
+    
+    // Stop before anything changes if the plan can't finish, a dry run shows its plan first
+    let preflight = match args.no_preflight {
+        true => Ok(()),
+        false => Preflight::new(&args).check(&plan, |_| false),
+    };
+    
+    // === Step 5: Run the plan ===
+    let mut run_report = RunReport::default();
+    run_report.add_plan(&plan);
+    output.collisions(&plan.collisions);
+    if args.dry {
+        // In dry run mode, just show what would happen
+        info!("DRY RUN: {} planned operations, nothing was changed", plan.operations.len());
+        output.plan(&plan);
+        output.finish(None);
+        preflight?;
+    } else {
+        preflight?;
+        let mut journal = Journal::create(&args.destination_dir)?;
+        journal.checkpoint(&plan)?;
+        info!("Starting run {}, undo it with `yee-haw undo {}`", journal.run_id(), journal.run_id());
+        run_report.run_id = Some(journal.run_id().to_string());
+        interrupt::install()?;
+        mover.execute(&plan, &mut run_report, &mut journal, output)?;
+        info!("Journal written to {}", journal.path().display());
+        if !args.quiet {
+            run_report.print_summary();
         }
+        output.finish(Some(&run_report));
     }
     
+    finish_run_report(&args, &run_report)?;
+    
     info!("Operation complete.");
+    Ok(run_report.exit_code())
+}
+
+/// Catch invalid arguments before anything is scanned
+fn validate(args: &YeeArgs) -> anyhow::Result<()> {
+    if !Path::new(&args.source_dir).is_dir() {
+        return Err(ConfigError(format!("Source directory {} does not exist", args.source_dir)).into());
+    }
+    if let Err(e) = glob::Pattern::new(&args.query) {
+        return Err(ConfigError(format!("Invalid query '{}': {}", args.query, e)).into());
+    }
+    if args.max_errors == Some(0) {
+        return Err(ConfigError("--max-errors must be at least 1".to_string()).into());
+    }
+    if args.copy_jobs == 0 || args.device_jobs == Some(0) {
+        return Err(ConfigError("--copy-jobs and --device-jobs must be at least 1".to_string()).into());
+    }
+    if args.ops_limit == Some(0) {
+        return Err(ConfigError("--ops-limit must be at least 1".to_string()).into());
+    }
+    if args.copy_engine == CopyEngine::IoUring && !copy::IO_URING {
+        return Err(ConfigError("--copy-engine io-uring needs a Linux build with --features io-uring".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Take the destination's lock, following `--on-locked` when another run holds it.
+/// `None` if the run is to carry on read-only, which only runs that can be dry allow.
+fn lock_destination(args: &YeeArgs, can_read_only: bool) -> anyhow::Result<Option<DestinationLock>> {
+    let policy = match args.on_locked {
+        LockPolicy::ReadOnly if !can_read_only => LockPolicy::Fail,
+        policy => policy,
+    };
+    DestinationLock::acquire(&args.destination_dir, policy)
+}
+
+/// Finish an interrupted run from the plan it checkpointed and its journal
+fn resume(args: &YeeArgs, run_id: &str, output: &mut Output) -> anyhow::Result<i32> {
+    let mut journal = Journal::open(&args.destination_dir, run_id)?;
+    let plan = journal.checkpointed_plan()?;
+    info!(
+        "Resuming run {}: {} of {} operations already done",
+        run_id,
+        journal.done_count(),
+        plan.operations.len()
+    );
+    
+    if !args.no_preflight {
+        Preflight::new(args).check(&plan, |index| journal.is_done(index))?;
+    }
+    
+    let mut run_report = RunReport::default();
+    run_report.add_plan(&plan);
+    run_report.run_id = Some(run_id.to_string());
+    interrupt::install()?;
+    output.collisions(&plan.collisions);
+    Mover::new(args.clone()).execute(&plan, &mut run_report, &mut journal, output)?;
+    if !args.quiet {
+        run_report.print_summary();
+    }
+    output.finish(Some(&run_report));
+    
+    finish_run_report(args, &run_report)?;
+    info!("Run {} complete.", run_id);
+    Ok(run_report.exit_code())
+}
+
+/// Log the run report and write it out if requested
+fn finish_run_report(args: &YeeArgs, report: &RunReport) -> anyhow::Result<()> {
+    report.log_summary();
+    if let Some(path) = &args.run_report {
+        report.write(path)?;
+    }
+    Ok(())
+}
+
+/// Log the duplicates report and write it out if requested
+fn finish_report(args: &YeeArgs, report: &DupesReport) -> anyhow::Result<()> {
+    report.log_summary();
+    if let Some(path) = &args.report {
+        report.write(path)?;
+    }
     Ok(())
 }
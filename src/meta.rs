@@ -1,16 +1,21 @@
-use std::fs::{self, File};
+use std::fs::File;
 use std::io::{BufReader, BufRead};
 use std::path::{Path, PathBuf};
 use xxhash_rust::xxh3::xxh3_64;
-use log::{debug, trace, info};
+use log::{debug, trace};
 use crate::yee_file::YeeFile;
-use crate::args::{YeeArgs, RenameStyle, GroupStyle};
+use crate::args::{YeeArgs, RenameStyle, GroupStyle, CollisionPolicy};
+use crate::dedup_key::DedupKeys;
+use crate::progress;
+use crate::throttle;
 use std::collections::HashMap;
 
 /// 2nd stage in our file copier. Will take the list of files from the scanner and add
 /// any additional metadata to the files. This includes the hash and destination paths.
 pub struct Meta {
     args: YeeArgs,
+    // Picks the dedup key extractor for each file
+    dedup_keys: DedupKeys,
     // Track group counts for incremental group naming
     group_counters: HashMap<String, usize>,
     // Track file counts for incremental file naming
@@ -20,6 +25,7 @@ pub struct Meta {
 impl Meta {
     pub fn new(args: YeeArgs) -> Self {
         Self {
+            dedup_keys: DedupKeys::new(args.content_key, &args.dedup_keys),
             args,
             group_counters: HashMap::new(),
             file_counters: HashMap::new(),
@@ -27,24 +33,43 @@ impl Meta {
     }
     
     /// Process a list of YeeFiles, adding metadata (hash and destination paths) to each file
-    pub fn process(&mut self, files: &mut Vec<YeeFile>) -> anyhow::Result<()> {
+    pub fn process(&mut self, files: &mut [YeeFile]) -> anyhow::Result<()> {
         debug!("Processing {} files to add metadata", files.len());
         
-        // First pass: calculate hashes
+        // First pass: calculate hashes and dedup keys. Reading every byte of a file is
+        // the slow part, so the hash is only computed when something uses it
+        let hash_all = self.hash_all();
+        let total_bytes = files.iter().map(|f| f.size).sum();
+        let progress = progress::start("Hashing", Some(files.len() as u64), Some(total_bytes));
         for file in files.iter_mut() {
             let full_path = format!("{}/{}.{}", file.source_full_path, file.filename, file.extension);
             let path = Path::new(&full_path);
+            progress::file(&full_path);
+            throttle::op();
             
-            match self.hash_file(path) {
-                Ok(hash) => {
-                    trace!("Added hash {} to file {}.{}", &hash, file.filename, file.extension);
-                    file.hash = Some(hash);
+            let extractor = self.dedup_keys.for_file(file);
+            if hash_all || extractor.needs_hash() {
+                add_hash(file, path);
+            }
+            
+            // Only record a dedup key when it isn't just the hash again
+            match extractor.key(file, path) {
+                Ok(Some(key)) if Some(&key) != file.hash.as_ref() => {
+                    let key = format!("{}:{}", extractor.name(), key);
+                    trace!("Added dedup key {} to file {}.{}", &key, file.filename, file.extension);
+                    file.dedup_key = Some(key);
                 },
+                Ok(Some(_)) => {},
+                // The store falls back to the hash for files without a key
+                Ok(None) => add_hash(file, path),
                 Err(e) => {
-                    debug!("Failed to calculate hash for {}: {}", full_path, e);
+                    debug!("Failed to compute {} key for {}: {}", extractor.name(), full_path, e);
+                    add_hash(file, path);
                 }
             }
+            progress::advance(file.size);
         }
+        drop(progress);
         
         // Second pass: create destination paths
         for file in files.iter_mut() {
@@ -54,6 +79,21 @@ impl Meta {
         Ok(())
     }
     
+    /// Whether options other than the dedup keys use the hash of every file: hash based
+    /// names, hash suffixes for collisions and the directory analysis
+    fn hash_all(&self) -> bool {
+        let args = &self.args;
+        let hash_names = match args.rename_style {
+            RenameStyle::ShortHash => true,
+            RenameStyle::Combined => args.group_style == GroupStyle::ShortHash,
+            _ => false,
+        };
+        hash_names
+            || args.on_collision == CollisionPolicy::HashSuffix
+            || args.dir_dupes
+            || args.similar_dirs.is_some()
+    }
+    
     /// Set destination paths for a file based on args settings
     fn set_destination_paths(&mut self, file: &mut YeeFile) -> anyhow::Result<()> {
         // Create the group folder name based on the selected group style
@@ -62,22 +102,19 @@ impl Meta {
         // Create the destination filename based on the selected rename style
         let dest_filename = self.get_destination_filename(file);
         
-        // Create the full destination path
+        // Create the full destination path. Directories are only created when the plan runs
         let dest_path = PathBuf::from(&self.args.destination_dir)
             .join(&group_folder);
         
-        // Create the directory structure if it doesn't exist
-        fs::create_dir_all(&dest_path)?;
-        
         // Set the destination paths in the YeeFile
         // destination_full_path should only contain the directory path, not the filename
         file.destination_full_path = dest_path.to_string_lossy().to_string();
         file.destination_local_path = group_folder;
-        file.filename = dest_filename;
+        file.destination_filename = dest_filename;
         
         debug!("Set destination for {}.{}: {}", 
             file.filename, file.extension, 
-            file.destination_full_path);
+            file.destination_path());
         
         Ok(())
     }
@@ -170,32 +207,50 @@ impl Meta {
             }
         }
     }
+}
+
+/// Set the hash of a file whose source is at `path`, unless it already has one
+fn add_hash(file: &mut YeeFile, path: &Path) {
+    if file.hash.is_some() {
+        return;
+    }
+    match hash_file(path) {
+        Ok(hash) => {
+            trace!("Added hash {} to file {}.{}", &hash, file.filename, file.extension);
+            file.hash = Some(hash);
+        },
+        Err(e) => {
+            debug!("Failed to calculate hash for {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Hash a file using xxHash algorithm (non-cryptographic, very fast).
+/// Shared with the mover, which re-hashes files to verify them.
+pub fn hash_file(path: &Path) -> anyhow::Result<String> {
+    // Open the file and create a buffered reader
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    let mut hasher = xxh3_64(b"");
     
-    /// Hash a file using xxHash algorithm (non-cryptographic, very fast)
-    fn hash_file(&self, path: &Path) -> anyhow::Result<String> {
-        // Open the file and create a buffered reader
-        let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
-        let mut hasher = xxh3_64(b"");
-        
-        // Read the file in chunks and update the hash
-        loop {
-            let buf = reader.fill_buf()?;
-            let buf_len = buf.len();
-            if buf_len == 0 {
-                break;
-            }
-            
-            // Update the hash with this chunk
-            hasher = xxh3_64_with_seed(buf, hasher);
-            
-            // Move the reader's cursor
-            reader.consume(buf_len);
+    // Read the file in chunks and update the hash
+    loop {
+        let buf = reader.fill_buf()?;
+        let buf_len = buf.len();
+        if buf_len == 0 {
+            break;
         }
         
-        // Convert the hash to a string
-        Ok(format!("{:016x}", hasher))
+        // Update the hash with this chunk
+        hasher = xxh3_64_with_seed(buf, hasher);
+        throttle::bytes(buf_len as u64);
+        
+        // Move the reader's cursor
+        reader.consume(buf_len);
     }
+    
+    // Convert the hash to a string
+    Ok(format!("{:016x}", hasher))
 }
 
 /// Helper function to incrementally update an xxHash
@@ -205,3 +260,34 @@ fn xxh3_64_with_seed(data: &[u8], seed: u64) -> u64 {
     bytes.extend_from_slice(data);
     xxh3_64(&bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn process(dir: &Path, options: &[&str]) -> Vec<YeeFile> {
+        let root = dir.to_str().unwrap();
+        let mut argv = vec!["yee-haw", "-s", root, "-d", root];
+        argv.extend_from_slice(options);
+        let mut files = vec![YeeFile::from_path(dir, &dir.join("a.txt")).unwrap()];
+        Meta::new(YeeArgs::parse_from(argv)).process(&mut files).unwrap();
+        files
+    }
+
+    #[test]
+    fn hashes_only_when_something_needs_it() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "some text").unwrap();
+
+        let files = process(dir.path(), &["--content-key", "name-size", "--rename-style", "none"]);
+        assert!(files[0].hash.is_none());
+        assert!(files[0].dedup_key.is_some());
+
+        let files = process(dir.path(), &["--content-key", "name-size", "--rename-style", "none", "--dir-dupes"]);
+        assert!(files[0].hash.is_some());
+
+        let files = process(dir.path(), &["--rename-style", "none"]);
+        assert!(files[0].hash.is_some());
+    }
+}
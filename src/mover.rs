@@ -1,242 +1,831 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use anyhow::{bail, Context};
 use log::{debug, info, warn};
-use crate::yee_file::YeeFile;
-use crate::args::YeeArgs;
-use std::collections::HashMap;
+use crate::args::{ConflictPolicy, CopyEngine, Preserve, YeeArgs};
+use crate::copy;
+use crate::interrupt;
+use crate::job_queue::{Batch, Job, JobQueue};
+use crate::journal::{Action, Journal, JournalEntry};
+use crate::meta::hash_file;
+use crate::output::{OperationRecord, Outcome, Output};
+use crate::plan::{OpKind, Operation, Plan};
+use crate::preserve;
+use crate::progress;
+use crate::throttle;
+use crate::report::{Conflict, OperationFailure, PreserveFailure, RunReport, SkippedOperation};
 use std::io::Write;
 
-/// Final stage in our file processing pipeline. Takes the files that have been 
-/// fully processed and moves or copies them to their destination.
-/// 
-/// Also generates metadata YAML files in the destination's .yeehaw directories.
-/// If duplicate tracking is enabled, duplicates will be moved to a "_dupes" directory
-/// within the destination directory.
-/// 
+/// Most small copies handed to a worker at once with `--copy-engine io-uring`
+const BATCH_SIZE: usize = 32;
+
+/// Final stage in our file processing pipeline. Executes the `Plan` built from the
+/// fully processed files: creates the destination folders, writes the metadata YAML
+/// files in the destination's .yeehaw directories and moves or copies each file.
+///
+/// Duplicates have already been routed to the "_dupes" directory by the planner,
+/// and hardlinks to be preserved are planned as links.
+///
 /// When copy_mode is enabled, files are copied instead of moved, preserving the originals.
+/// Files that already exist at their destination are handled by `--on-conflict`.
+/// With `--copy-jobs` above 1, copies and moves run on a pool of worker threads.
+/// File data is copied with the `--copy-engine`.
 pub struct Mover {
     args: YeeArgs,
 }
 
+/// What to do with a file whose destination may already exist
+enum Target {
+    /// Write the file to this path
+    Write(String),
+    /// Leave the file where it is, for this reason
+    Skip(String),
+    /// An identical file is already at the destination
+    Organized,
+}
+
+/// Everything executing a plan updates. The copy workers share it behind a mutex, so
+/// journal entries, report entries and output records are written one at a time.
+struct RunState<'a> {
+    report: &'a mut RunReport,
+    journal: &'a mut Journal,
+    output: &'a mut Output,
+    /// Destinations planned or written by this run, which resolving a conflict must not pick
+    taken: HashSet<PathBuf>,
+    /// Where the file planned for a destination was written, after `--on-conflict`,
+    /// once it is. Links to that destination point there.
+    written: HashMap<String, String>,
+}
+
+/// A copy, move or link whose destination is decided
+enum Transfer {
+    /// Nothing to do, with the record saying why
+    Skipped(Box<OperationRecord>),
+    /// Journaled as begun, ready to run
+    Ready(Ready),
+}
+
+struct Ready {
+    action: Action,
+    destination: String,
+    /// The file a link points at, as written by this run
+    link_target: Option<String>,
+    entry: JournalEntry,
+}
+
+/// What writing a file did besides putting the data in place
+#[derive(Default, Debug)]
+pub struct Moved {
+    /// Attributes that could not be carried over to a copy
+    pub preserve_failures: Vec<PreserveFailure>,
+    /// Why the source is still there after being copied, if it couldn't be deleted
+    pub source_kept: Option<String>,
+    /// Hash of the data written, if it was computed to verify it
+    pub hash: Option<String>,
+}
+
 impl Mover {
     /// Creates a new Mover instance
     pub fn new(args: YeeArgs) -> Self {
         Self { args }
     }
 
-    /// Moves or copies the given files to their destination paths based on copy_mode.
-    /// 
-    /// Each file's destination_full_path should already be set.
-    pub fn move_files(&self, files: Vec<YeeFile>) -> anyhow::Result<()> {
-        let action = if self.args.copy_mode { "Copying" } else { "Moving" };
-        info!("{} {} files to their destination", action, files.len());
-        
-        // Group files by group_id for metadata tracking
-        let mut grouped_files: HashMap<String, Vec<YeeFile>> = HashMap::new();
-        
-        for file in &files {
-            grouped_files
-                .entry(file.group_id.clone())
-                .or_insert_with(Vec::new)
-                .push(file.clone());
-        }
-        
-        // Create metadata for each group
-        for (group_id, group_files) in &grouped_files {
-            self.write_group_metadata(group_id, group_files)?;
-        }
-        
-        for file in files {
-            self.process_single_file(file)?;
-        }
-        
-        let action_complete = if self.args.copy_mode { "File copying" } else { "File moving" };
-        info!("{} complete", action_complete);
-        Ok(())
-    }
+    /// Applies every operation of the plan, recording each one in the journal.
+    /// Operations the journal has as done by an earlier attempt at the run are skipped.
+    ///
+    /// Folders and metadata files are written first, in plan order. Copies and moves
+    /// follow, grouped by destination folder and run by `--copy-jobs` workers with at
+    /// most `--device-jobs` per device; hardlinks to the copied files come last.
+    ///
+    /// After SIGINT or SIGTERM the operations in flight are finished and the rest are
+    /// left for `yee-haw resume`, with `report.interrupted` set.
+    ///
+    /// Failing to create a folder or write to the journal stops the run. Metadata and
+    /// files that can't be written are logged and tallied in the report, until
+    /// `--fail-fast` or `--max-errors` says to stop.
+    ///
+    /// What happened to each operation goes to `output`.
+    pub fn execute(
+        &self,
+        plan: &Plan,
+        report: &mut RunReport,
+        journal: &mut Journal,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        // A resumed plan may have been built with a different copy mode than the current args
+        let copy_mode = plan.count(OpKind::Move) == 0;
+        let action = if copy_mode { "Copying" } else { "Moving" };
+        info!(
+            "{} {} files to their destination ({} operations)",
+            action,
+            plan.count(OpKind::Copy) + plan.count(OpKind::Move) + plan.count(OpKind::Link),
+            plan.operations.len()
+        );
+
+        let transfers: Vec<&Operation> = plan
+            .operations
+            .iter()
+            .enumerate()
+            .filter(|(index, op)| op.source.is_some() && !journal.is_done(*index))
+            .map(|(_, op)| op)
+            .collect();
+        let _progress = progress::start(
+            if copy_mode { "Copying" } else { "Moving" },
+            Some(transfers.len() as u64),
+            Some(transfers.iter().map(|op| op.size).sum()),
+        );
+
+        let steps = |kinds: &[OpKind]| -> Vec<usize> {
+            (0..plan.operations.len())
+                .filter(|&index| kinds.contains(&plan.operations[index].kind))
+                .collect()
+        };
+        // Files of the same folder are written together, which is kinder to network shares and USB drives
+        let mut copies = steps(&[OpKind::Copy, OpKind::Move]);
+        copies.sort_by_key(|&index| Path::new(&plan.operations[index].destination).parent().map(Path::to_path_buf));
+
+        // Files written by an earlier attempt at the run are where its journal says
+        let written = plan
+            .operations
+            .iter()
+            .enumerate()
+            .filter_map(|(index, op)| Some((op.destination.clone(), journal.written_to(index)?.to_string())))
+            .collect();
+        let mut state = RunState {
+            report,
+            journal,
+            output,
+            taken: plan
+                .operations
+                .iter()
+                .filter(|op| matches!(op.kind, OpKind::Copy | OpKind::Move | OpKind::Link))
+                .map(|op| PathBuf::from(&op.destination))
+                .collect(),
+            written,
+        };
+        let finished = self.run_in_order(plan, &steps(&[OpKind::Mkdir, OpKind::WriteMetadata]), &mut state)?
+            && self.run_parallel(plan, &copies, &mut state)?
+            && self.run_in_order(plan, &steps(&[OpKind::Link]), &mut state)?;
 
-    /// Moves or copies duplicate files to the _dupes directory based on copy_mode.
-    /// 
-    /// Duplicates are stored in destination_dir/_dupes/ with the same structure
-    /// as the originals would have in the destination directory.
-    pub fn move_duplicates(&self, duplicates: Vec<YeeFile>) -> anyhow::Result<()> {
-        if duplicates.is_empty() {
+        if !finished {
+            state.report.operations_remaining =
+                plan.operations.len() - state.journal.done_count() - state.report.operations_done;
             return Ok(());
         }
-
-        let action = if self.args.copy_mode { "Copying" } else { "Moving" };
-        info!("{} {} duplicate files to dupes directory", action, duplicates.len());
-        
-        // Group duplicate files by group_id for metadata tracking
-        let mut grouped_dupes: HashMap<String, Vec<YeeFile>> = HashMap::new();
-        
-        for file in &duplicates {
-            grouped_dupes
-                .entry(file.group_id.clone())
-                .or_insert_with(Vec::new)
-                .push(file.clone());
-        }
-        
-        // Create metadata for duplicate groups
-        for (group_id, group_files) in &grouped_dupes {
-            self.write_group_metadata(group_id, group_files)?;
-        }
-        
-        for file in duplicates {
-            self.process_duplicate_file(file)?;
-        }
-        
-        let action_complete = if self.args.copy_mode { "Duplicate file copying" } else { "Duplicate file moving" };
+        let action_complete = if copy_mode { "File copying" } else { "File moving" };
         info!("{} complete", action_complete);
         Ok(())
     }
 
-    /// Writes metadata for a group of files to a YAML file in the .yeehaw directory
-    fn write_group_metadata(&self, group_id: &str, files: &[YeeFile]) -> anyhow::Result<()> {
-        // Base path for the destination directory
-        let dest_root = Path::new(&self.args.destination_dir);
-        
-        // Get the path to the group's first file to determine where to store metadata
-        if let Some(first_file) = files.first() {
-            // Create a .yeehaw directory in the destination directory that contains the group
-            let group_path = if first_file.destination_local_path.is_empty() {
-                dest_root.to_path_buf()
-            } else {
-                dest_root.join(&first_file.destination_local_path)
-            };
-            
-            let yeehaw_dir = group_path.join(".yeehaw");
-            
-            // Create the .yeehaw directory if it doesn't exist
-            fs::create_dir_all(&yeehaw_dir)?;
-            
-            // Create a YAML file for each file's metadata
-            for file in files {
-                let metadata_filename = format!("{}_{}_{}.yaml", 
-                    group_id,
-                    file.filename, 
-                    file.extension);
-                let metadata_path = yeehaw_dir.join(metadata_filename);
-                
-                // Serialize the YeeFile to YAML
-                let yaml_content = serde_yaml::to_string(file)?;
-                
-                // Write the YAML content to a file
-                let mut file = fs::File::create(metadata_path)?;
-                file.write_all(yaml_content.as_bytes())?;
+    /// Runs the given plan operations one after the other. Returns false if the run
+    /// was stopped before all of them ran.
+    fn run_in_order(&self, plan: &Plan, indices: &[usize], state: &mut RunState) -> anyhow::Result<bool> {
+        for &index in indices {
+            let operation = &plan.operations[index];
+            if state.journal.is_done(index) {
+                state.output.operation(OperationRecord::new(index, operation, Outcome::AlreadyDone));
+                continue;
             }
-            
-            // Write a group summary file
-            let group_summary_path = yeehaw_dir.join(format!("{}_summary.yaml", group_id));
-            
-            // Create a summary struct with group info
-            #[derive(serde::Serialize)]
-            struct GroupSummary {
-                group_id: String,
-                file_count: usize,
-                files: Vec<String>,
+            // Stop between operations, so nothing is left half written
+            if self.stop_requested(state, &operation.destination) {
+                return Ok(false);
             }
-            
-            let summary = GroupSummary {
-                group_id: group_id.to_string(),
-                file_count: files.len(),
-                files: files.iter()
-                    .map(|f| format!("{}.{}", f.filename, f.extension))
-                    .collect(),
+            throttle::op();
+            let record = match operation.kind {
+                OpKind::Mkdir => {
+                    self.create_dir(index, &operation.destination, state.journal)?;
+                    OperationRecord::new(index, operation, Outcome::Succeeded)
+                },
+                OpKind::WriteMetadata => {
+                    let entry =
+                        state.journal.begin(index, Action::WriteMetadata, None, &operation.destination, None)?;
+                    match self.write_metadata(operation) {
+                        Ok(()) => {
+                            state.journal.done(entry)?;
+                            OperationRecord::new(index, operation, Outcome::Succeeded)
+                        }
+                        Err(e) => {
+                            warn!("Failed to write metadata to {}: {}", operation.destination, e);
+                            let error = format!("failed to write metadata: {}", e);
+                            state.report.failures.push(OperationFailure {
+                                source: None,
+                                destination: operation.destination.clone(),
+                                error: error.clone(),
+                            });
+                            state.journal.failed(entry)?;
+                            OperationRecord::new(index, operation, Outcome::Failed).with_detail(error)
+                        }
+                    }
+                },
+                OpKind::Copy | OpKind::Move | OpKind::Link => {
+                    progress::file(&operation.destination);
+                    let record = match self.prepare(index, operation, state)? {
+                        Transfer::Skipped(record) => *record,
+                        Transfer::Ready(ready) => {
+                            let result = self.perform(operation, &ready);
+                            self.complete(index, operation, ready, result, state)?
+                        }
+                    };
+                    if operation.source.is_some() {
+                        progress::advance(operation.size);
+                    }
+                    record
+                },
             };
-            
-            // Serialize and write the summary
-            let summary_yaml = serde_yaml::to_string(&summary)?;
-            let mut summary_file = fs::File::create(group_summary_path)?;
-            summary_file.write_all(summary_yaml.as_bytes())?;
-            
-            debug!("Wrote group summary for {} to YAML in {}", group_id, yeehaw_dir.display());
-        }
-        
+            state.output.operation(record);
+            state.report.operations_done += 1;
+        }
+        Ok(true)
+    }
+
+    /// Runs copies and moves on `--copy-jobs` worker threads. Deciding where a file
+    /// goes and journaling it happen under the state lock, the copying itself doesn't.
+    /// Returns false if the run was stopped before all of them ran.
+    fn run_parallel(&self, plan: &Plan, indices: &[usize], state: &mut RunState) -> anyhow::Result<bool> {
+        let workers = self.args.copy_jobs.max(1);
+        let batched = self.args.copy_engine == CopyEngine::IoUring;
+        if workers == 1 && self.args.device_jobs.is_none() && !batched {
+            return self.run_in_order(plan, indices, state);
+        }
+
+        let mut jobs = Vec::new();
+        for &index in indices {
+            let operation = &plan.operations[index];
+            if state.journal.is_done(index) {
+                state.output.operation(OperationRecord::new(index, operation, Outcome::AlreadyDone));
+                continue;
+            }
+            let small = operation.kind == OpKind::Copy && operation.size <= copy::SMALL_FILE;
+            jobs.push(Job::new(index, devices(operation), batched && small));
+        }
+        debug!("Running {} transfers on {} workers", jobs.len(), workers);
+        let queue = JobQueue::new(jobs, self.args.device_jobs.unwrap_or(workers));
+        let shared = Mutex::new(state);
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| scope.spawn(|| self.work(plan, &queue, &shared)))
+                .collect();
+            let mut result = Ok(());
+            for handle in handles {
+                match handle.join() {
+                    Ok(Err(e)) if result.is_ok() => result = Err(e),
+                    Ok(_) => {}
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            }
+            result
+        })?;
+
+        let state = shared.into_inner().unwrap_or_else(PoisonError::into_inner);
+        Ok(!state.report.interrupted && !state.report.error_limit_reached)
+    }
+
+    /// A copy worker: takes transfers off the queue until it is empty or stopped
+    fn work(&self, plan: &Plan, queue: &JobQueue, shared: &Mutex<&mut RunState>) -> anyhow::Result<()> {
+        while let Some(batch) = queue.next(BATCH_SIZE) {
+            if let Err(e) = self.work_on(plan, &batch, queue, shared) {
+                // The journal can't be trusted anymore, every worker stops
+                queue.stop();
+                return Err(e);
+            }
+        }
         Ok(())
     }
 
-    /// Processes a single file (either copy or move based on copy_mode)
-    fn process_single_file(&self, file: YeeFile) -> anyhow::Result<()> {
-        let source_path = format!("{}/{}.{}", file.source_full_path, file.filename, file.extension);
-        let destination_path = format!("{}/{}.{}", file.destination_full_path, file.filename, file.extension);
-        
-        let action = if self.args.copy_mode { "Copying" } else { "Moving" };
-        debug!("{} file from {} to {}", action, source_path, destination_path);
-        
-        // Ensure the directory exists
-        if let Some(parent) = Path::new(&destination_path).parent() {
-            fs::create_dir_all(parent)?;
+    fn work_on(
+        &self,
+        plan: &Plan,
+        batch: &Batch<'_>,
+        queue: &JobQueue,
+        shared: &Mutex<&mut RunState>,
+    ) -> anyhow::Result<()> {
+        let mut records = Vec::new();
+        let mut prepared = Vec::new();
+        for _ in &batch.indices {
+            throttle::op();
         }
-        
-        // Copy the file
-        match fs::copy(&source_path, &destination_path) {
-            Ok(_) => {
-                debug!("Successfully copied file to {}", destination_path);
-                
-                // If not in copy mode (i.e., move mode), delete the source file
-                if !self.args.copy_mode {
-                    if let Err(e) = fs::remove_file(&source_path) {
-                        warn!("Failed to delete source file {}: {}", source_path, e);
-                    } else {
-                        debug!("Deleted source file after move: {}", source_path);
-                    }
+        {
+            let mut state = lock(shared);
+            for &index in &batch.indices {
+                let operation = &plan.operations[index];
+                if self.stop_requested(&mut state, &operation.destination) {
+                    queue.stop();
+                    break;
                 }
-            },
-            Err(e) => warn!("Failed to copy file to {}: {}", destination_path, e),
+                progress::file(&operation.destination);
+                match self.prepare(index, operation, &mut state)? {
+                    Transfer::Skipped(record) => records.push(*record),
+                    Transfer::Ready(ready) => prepared.push((index, ready)),
+                }
+            }
         }
 
+        let results = self.perform_batch(plan, &prepared);
+        let mut state = lock(shared);
+        for ((index, ready), result) in prepared.into_iter().zip(results) {
+            records.push(self.complete(index, &plan.operations[index], ready, result, &mut state)?);
+        }
+        for record in records {
+            progress::advance(plan.operations[record.index].size);
+            state.output.operation(record);
+            state.report.operations_done += 1;
+        }
         Ok(())
     }
 
-    /// Processes a duplicate file (either copy or move based on copy_mode)
-    /// 
-    /// Duplicates are stored in destination_dir/_dupes/ with the same structure
-    /// as the originals would have in the destination directory.
-    fn process_duplicate_file(&self, file: YeeFile) -> anyhow::Result<()> {
-        let source_path = format!("{}/{}.{}", file.source_full_path, file.filename, file.extension);
-        
-        // Create a path for duplicates: destination_dir/_dupes/[original_destination_structure]
-        let dest_root = Path::new(&self.args.destination_dir);
-        let dupes_dir = dest_root.join("_dupes");
-        
-        // Keep the same destination layout but under the _dupes directory
-        let relative_dest_path = if let Ok(rel_path) = Path::new(&file.destination_full_path)
-            .strip_prefix(Path::new(&self.args.destination_dir)) {
-            rel_path
-        } else {
-            // Fallback if we can't determine the relative path
-            Path::new(&file.destination_local_path)
+    /// Performs the transfers of a batch. The plain copies go through
+    /// `copy::copy_batch` together, everything else one at a time.
+    fn perform_batch(&self, plan: &Plan, prepared: &[(usize, Ready)]) -> Vec<anyhow::Result<Moved>> {
+        let batched = |(index, ready): &&(usize, Ready)| {
+            let operation = &plan.operations[*index];
+            let engine = self.args.copy_engine;
+            engine == CopyEngine::IoUring && ready.action == Action::Copy && operation.kind == OpKind::Copy
         };
-        
-        let dupe_dest_path = dupes_dir.join(relative_dest_path);
-        let dupe_dest_path_str = dupe_dest_path.to_string_lossy().to_string();
-        
-        let action = if self.args.copy_mode { "Copying" } else { "Moving" };
-        debug!("{} duplicate file from {} to {}", action, source_path, dupe_dest_path_str);
-        
-        // Ensure the directory exists
-        if let Some(parent) = dupe_dest_path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        
-        // Copy the file
-        match fs::copy(&source_path, &dupe_dest_path) {
-            Ok(_) => {
-                debug!("Successfully copied duplicate file to {}", dupe_dest_path_str);
-                
-                // If not in copy mode (i.e., move mode), delete the source file
-                if !self.args.copy_mode {
-                    if let Err(e) = fs::remove_file(&source_path) {
-                        warn!("Failed to delete duplicate source file {}: {}", source_path, e);
-                    } else {
-                        debug!("Deleted source file after duplicate move: {}", source_path);
-                    }
+        let pairs: Vec<(&Path, &Path)> = prepared
+            .iter()
+            .filter(batched)
+            .map(|(index, ready)| {
+                let source = plan.operations[*index].source.as_deref().unwrap_or_default();
+                (Path::new(source), Path::new(&ready.destination))
+            })
+            .collect();
+        let mut copied = copy::copy_batch(&pairs).into_iter();
+
+        prepared
+            .iter()
+            .map(|transfer| {
+                let (index, ready) = transfer;
+                let operation = &plan.operations[*index];
+                let result = if batched(&transfer) { copied.next() } else { None };
+                match result {
+                    Some(result) => self.copied(operation, &ready.destination, result),
+                    None => self.perform(operation, ready),
                 }
-            },
-            Err(e) => warn!("Failed to copy duplicate file to {}: {}", dupe_dest_path_str, e),
+            })
+            .collect()
+    }
+
+    /// Whether to stop before the next operation, after SIGINT or SIGTERM or once
+    /// `--fail-fast` or `--max-errors` is reached. Flags the report the first time.
+    fn stop_requested(&self, state: &mut RunState, next: &str) -> bool {
+        let report = &mut *state.report;
+        if report.interrupted || report.error_limit_reached {
+            return true;
         }
+        let error_limit = if self.args.fail_fast { Some(1) } else { self.args.max_errors };
+        let error_limit_reached = error_limit.is_some_and(|limit| report.failures.len() >= limit);
+        if !interrupt::requested() && !error_limit_reached {
+            return false;
+        }
+        report.interrupted = !error_limit_reached;
+        report.error_limit_reached = error_limit_reached;
+        let reason = if error_limit_reached { "Too many failures" } else { "Interrupted" };
+        warn!("{}, stopping before {}", reason, next);
+        true
+    }
+
+    /// Creates a directory and any missing parents, journaling each one so undo can remove them
+    fn create_dir(&self, index: usize, directory: &str, journal: &mut Journal) -> anyhow::Result<()> {
+        let missing: Vec<&Path> = Path::new(directory)
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+            .collect();
+        for dir in missing.into_iter().rev() {
+            let entry = journal.begin(index, Action::Mkdir, None, &dir.to_string_lossy(), None)?;
+            fs::create_dir(dir)?;
+            journal.done(entry)?;
+            debug!("Created directory {}", dir.display());
+        }
+        Ok(())
+    }
 
+    /// Writes a metadata YAML file planned for a .yeehaw directory
+    fn write_metadata(&self, operation: &Operation) -> anyhow::Result<()> {
+        let content = operation.content.as_deref().unwrap_or_default();
+        let mut file = fs::File::create(&operation.destination)?;
+        file.write_all(content.as_bytes())?;
+        debug!("Wrote metadata to {}", operation.destination);
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Decides where a file goes and journals the transfer as begun
+    fn prepare(&self, index: usize, operation: &Operation, state: &mut RunState) -> anyhow::Result<Transfer> {
+        // A link points at the file written for its target, wherever that ended up
+        let link_target = match &operation.link_to {
+            Some(target) => match state.written.get(target) {
+                Some(written) => Some(written.clone()),
+                None => {
+                    let reason = format!("link target {} was not written by this run", target);
+                    return Ok(Transfer::Skipped(Box::new(skip(index, operation, reason, state.report))));
+                }
+            },
+            None => None,
+        };
+
+        let (action, destination) = match self.resolve_conflict(index, operation, link_target.as_deref(), state) {
+            Target::Skip(reason) => {
+                return Ok(Transfer::Skipped(Box::new(skip(index, operation, reason, state.report))));
+            }
+            // Identical data is already in place, a move only has to remove the source
+            Target::Organized if operation.kind == OpKind::Copy || operation.source.is_none() => {
+                if operation.kind == OpKind::Copy {
+                    state.written.insert(operation.destination.clone(), operation.destination.clone());
+                }
+                let reason = "already organized".to_string();
+                return Ok(Transfer::Skipped(Box::new(skip(index, operation, reason, state.report))));
+            }
+            Target::Organized => (Action::RemoveSource, operation.destination.clone()),
+            Target::Write(destination) => match operation.kind {
+                OpKind::Link => (Action::Link, destination),
+                OpKind::Move => (Action::Move, destination),
+                _ => (Action::Copy, destination),
+            },
+        };
+
+        let (source, hash) = (operation.source.as_deref(), operation.hash.as_deref());
+        let entry = state.journal.begin(index, action, source, &destination, hash)?;
+        Ok(Transfer::Ready(Ready { action, destination, link_target, entry }))
+    }
+
+    /// Copies, moves or links a file to the destination `prepare` picked
+    fn perform(&self, operation: &Operation, ready: &Ready) -> anyhow::Result<Moved> {
+        let destination = ready.destination.as_str();
+        match ready.action {
+            Action::RemoveSource => {
+                let source_kept = remove_source(operation.source.as_deref().unwrap_or_default())
+                    .err()
+                    .map(|e| format!("{:#}", e));
+                Ok(Moved { source_kept, ..Moved::default() })
+            }
+            Action::Link => self.process_link(operation, destination, ready.link_target.as_deref().unwrap_or_default()),
+            _ => self.process_single_file(operation, destination),
+        }
+    }
+
+    /// Journals how a transfer ended and adds it to the report, returning its record
+    fn complete(
+        &self,
+        index: usize,
+        operation: &Operation,
+        ready: Ready,
+        result: anyhow::Result<Moved>,
+        state: &mut RunState,
+    ) -> anyhow::Result<OperationRecord> {
+        let Ready { action, destination, mut entry, .. } = ready;
+        let mut record = OperationRecord::new(index, operation, Outcome::Succeeded);
+        record.action = action.name();
+        record.destination = destination.clone();
+        match result {
+            Ok(moved) => {
+                if matches!(action, Action::Copy | Action::Move | Action::RemoveSource) {
+                    state.written.insert(operation.destination.clone(), destination.clone());
+                }
+                state.report.succeeded += 1;
+                state.report.preserve_failures.extend(moved.preserve_failures);
+                if let Some(error) = moved.source_kept {
+                    warn!("{}", error);
+                    state.report.sources_kept.push(OperationFailure {
+                        source: operation.source.clone(),
+                        destination,
+                        error,
+                    });
+                }
+                // Files not hashed while scanning get the hash verified on the way, for undo
+                if entry.hash.is_none() {
+                    entry.hash = moved.hash;
+                }
+                state.journal.done(entry)?;
+            }
+            Err(e) => {
+                warn!("{:#}", e);
+                let error = format!("{:#}", e);
+                state.report.failures.push(OperationFailure {
+                    source: operation.source.clone(),
+                    destination,
+                    error: error.clone(),
+                });
+                state.journal.failed(entry)?;
+                record = record.with_detail(error);
+                record.outcome = Outcome::Failed;
+            }
+        }
+        Ok(record)
+    }
+
+    /// Decide where a file goes when its destination already exists, recording the
+    /// conflict in the run report
+    fn resolve_conflict(
+        &self,
+        index: usize,
+        operation: &Operation,
+        link_target: Option<&str>,
+        state: &mut RunState,
+    ) -> Target {
+        let destination = Path::new(&operation.destination);
+        if fs::symlink_metadata(destination).is_err() {
+            return Target::Write(operation.destination.clone());
+        }
+
+        // The data being written: the source file, or the file a link points at
+        let data_path = operation.source.as_deref().or(link_target).unwrap_or_default();
+        // A copy cut short while overwriting may have left the file half written, finish it
+        let policy = match state.journal.was_overwriting(index) {
+            true => ConflictPolicy::Overwrite,
+            false => self.args.on_conflict,
+        };
+        let (target, resolution) = match policy {
+            ConflictPolicy::Skip => (Target::Skip("destination exists".to_string()), "skipped".to_string()),
+            ConflictPolicy::Overwrite => {
+                (Target::Write(operation.destination.clone()), "overwritten".to_string())
+            }
+            ConflictPolicy::Rename => {
+                let renamed = free_path(destination, &state.taken);
+                state.taken.insert(renamed.clone());
+                let renamed = renamed.to_string_lossy().to_string();
+                (Target::Write(renamed.clone()), format!("renamed to {}", renamed))
+            }
+            ConflictPolicy::IfNewer => {
+                let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+                match (modified(Path::new(data_path)), modified(destination)) {
+                    (Some(source), Some(existing)) if source > existing => (
+                        Target::Write(operation.destination.clone()),
+                        "overwritten, source is newer".to_string(),
+                    ),
+                    _ => (
+                        Target::Skip("destination is not older".to_string()),
+                        "skipped, destination is not older".to_string(),
+                    ),
+                }
+            }
+            ConflictPolicy::IfDifferent => {
+                // Hash both sides now, so the source is only removed if it still matches
+                match (hash_file(Path::new(data_path)), hash_file(destination)) {
+                    (Ok(source), Ok(existing)) if source == existing => {
+                        (Target::Organized, "already organized".to_string())
+                    }
+                    (Ok(_), Ok(_)) => (
+                        Target::Write(operation.destination.clone()),
+                        "overwritten, contents differ".to_string(),
+                    ),
+                    (Err(e), _) | (_, Err(e)) => (
+                        Target::Skip("destination exists, failed to compare".to_string()),
+                        format!("skipped, failed to compare: {}", e),
+                    ),
+                }
+            }
+        };
+
+        debug!("{} already exists: {}", operation.destination, resolution);
+        state.report.conflicts.push(Conflict {
+            destination: operation.destination.clone(),
+            source: data_path.to_string(),
+            resolution,
+        });
+        target
+    }
+
+    /// Processes a single file (either copy or move based on the operation)
+    fn process_single_file(&self, operation: &Operation, destination_path: &str) -> anyhow::Result<Moved> {
+        let Some(source_path) = operation.source.as_deref() else {
+            bail!("No source for {}, skipping", operation.destination);
+        };
+
+        let action = if operation.kind == OpKind::Copy { "Copying" } else { "Moving" };
+        debug!("{} file from {} to {} ({})", action, source_path, destination_path, operation.reason);
+
+        if operation.kind == OpKind::Move {
+            let expected_hash = operation.hash.as_deref();
+            return safe_move(source_path, destination_path, expected_hash, &self.args.preserve, self.args.copy_engine)
+                .with_context(|| format!("Failed to move file to {}, source kept", destination_path));
+        }
+
+        // Copy the file
+        let result = copy::copy_file(Path::new(source_path), Path::new(destination_path), self.args.copy_engine);
+        self.copied(operation, destination_path, result)
+    }
+
+    /// Finishes a copy: reports its failure, or gives the new file the `--preserve` attributes
+    fn copied(&self, operation: &Operation, destination_path: &str, result: io::Result<u64>) -> anyhow::Result<Moved> {
+        let source_path = operation.source.as_deref().unwrap_or_default();
+        result.with_context(|| format!("Failed to copy file to {}", destination_path))?;
+        debug!("Successfully copied file to {}", destination_path);
+        let preserve_failures = preserve::apply(Path::new(source_path), Path::new(destination_path), &self.args.preserve);
+        Ok(Moved { preserve_failures, ..Moved::default() })
+    }
+
+    /// Links a destination to `target`, a file already written by this plan, removing
+    /// the source afterwards when moving
+    fn process_link(&self, operation: &Operation, destination_path: &str, target: &str) -> anyhow::Result<Moved> {
+        // Linking never replaces a file, an existing one that is to be overwritten goes first
+        if Path::new(destination_path).exists() {
+            fs::remove_file(destination_path).with_context(|| format!("Failed to replace {}", destination_path))?;
+        }
+
+        fs::hard_link(target, destination_path)
+            .with_context(|| format!("Failed to link {} to {}", destination_path, target))?;
+        debug!("Linked {} to {}", destination_path, target);
+        let source_kept = operation
+            .source
+            .as_deref()
+            .and_then(|source_path| remove_source(source_path).err())
+            .map(|e| format!("{:#}", e));
+        Ok(Moved { source_kept, ..Moved::default() })
+    }
+}
+
+/// Leaves a file where it is, recording why in the report
+fn skip(index: usize, operation: &Operation, reason: String, report: &mut RunReport) -> OperationRecord {
+    report.skipped.push(SkippedOperation {
+        source: operation.source.clone().unwrap_or_default(),
+        destination: operation.destination.clone(),
+        reason: reason.clone(),
+    });
+    let mut record = OperationRecord::new(index, operation, Outcome::Skipped).with_detail(reason);
+    record.action = "skip";
+    record
+}
+
+/// Moves a file without ever losing it.
+///
+/// On the same filesystem this is an atomic rename. Across filesystems the file is
+/// copied to a temp file next to the destination, synced to disk and hashed; only
+/// if the hash matches `expected_hash` (computed while scanning) is it renamed into
+/// place and the source deleted. A rename keeps every attribute, a copy gets the
+/// ones in `preserve`.
+///
+/// Once the data is in place the move counts as done, even if the source can't be deleted.
+pub fn safe_move(
+    source_path: &str,
+    destination_path: &str,
+    expected_hash: Option<&str>,
+    preserve: &[Preserve],
+    engine: CopyEngine,
+) -> anyhow::Result<Moved> {
+    match fs::rename(source_path, destination_path) {
+        Ok(()) => {
+            debug!("Renamed {} to {}", source_path, destination_path);
+            return Ok(Moved::default());
+        }
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            debug!("{} is on another filesystem, copying and verifying", destination_path);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    copy_and_remove(source_path, destination_path, expected_hash, preserve, engine)
+}
+
+/// The move across filesystems of `safe_move`: copy, verify, rename into place and
+/// only then delete the source
+fn copy_and_remove(
+    source_path: &str,
+    destination_path: &str,
+    expected_hash: Option<&str>,
+    preserve: &[Preserve],
+    engine: CopyEngine,
+) -> anyhow::Result<Moved> {
+    let destination = Path::new(destination_path);
+    let temp_path = temp_path(destination);
+    let result = copy_verified(Path::new(source_path), &temp_path, expected_hash, engine)
+        .and_then(|hash| Ok(fs::rename(&temp_path, destination).map(|()| hash)?));
+    let hash = match result {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+    sync_parent(destination);
+    let preserve_failures = preserve::apply(Path::new(source_path), destination, preserve);
+
+    debug!("Verified copy of {} at {}", source_path, destination_path);
+    let source_kept = remove_source(source_path).err().map(|e| {
+        warn!("{:#}", e);
+        format!("{:#}", e)
+    });
+    Ok(Moved { preserve_failures, source_kept, hash: Some(hash) })
+}
+
+/// Copy `source` to `temp`, sync it to disk and check its hash against `expected_hash`,
+/// or against a fresh hash of the source when none was computed while scanning.
+/// Returns the verified hash.
+fn copy_verified(
+    source: &Path,
+    temp: &Path,
+    expected_hash: Option<&str>,
+    engine: CopyEngine,
+) -> anyhow::Result<String> {
+    copy::copy_file(source, temp, engine)?;
+    fs::File::open(temp)?.sync_all()?;
+
+    let expected = match expected_hash {
+        Some(hash) => hash.to_string(),
+        None => hash_file(source)?,
+    };
+    let actual = hash_file(temp)?;
+    if actual != expected {
+        bail!("copy hash {} does not match source hash {}", actual, expected);
+    }
+    Ok(actual)
+}
+
+/// Hidden temp file in the destination's directory, so the final rename stays on one filesystem
+pub fn temp_path(destination: &Path) -> PathBuf {
+    let name = destination.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    destination.with_file_name(format!(".{}.yeehaw-tmp", name))
+}
+
+/// Sync a directory so a rename into it survives a crash. Not every platform supports
+/// this, so failures are only logged.
+fn sync_parent(path: &Path) {
+    let Some(parent) = path.parent() else {
+        return;
+    };
+    if let Err(e) = fs::File::open(parent).and_then(|dir| dir.sync_all()) {
+        debug!("Failed to sync directory {}: {}", parent.display(), e);
+    }
+}
+
+/// The first `name_N.ext` next to `path` that doesn't exist yet and isn't `taken`
+fn free_path(path: &Path, taken: &HashSet<PathBuf>) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{}_{}{}", stem, n, extension)))
+        .find(|candidate| !taken.contains(candidate) && fs::symlink_metadata(candidate).is_err())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Deletes a source file once it has been moved
+pub fn remove_source(source_path: &str) -> anyhow::Result<()> {
+    fs::remove_file(Path::new(source_path))
+        .with_context(|| format!("Failed to delete source file {}", source_path))?;
+    debug!("Deleted source file after move: {}", source_path);
+    Ok(())
+}
+
+/// Devices a transfer reads from and writes to, for `--device-jobs`
+fn devices(operation: &Operation) -> Vec<u64> {
+    let source = operation
+        .file
+        .as_ref()
+        .and_then(|file| file.device)
+        .or_else(|| operation.source.as_deref().and_then(|source| device(Path::new(source))));
+    let destination = Path::new(&operation.destination).parent().and_then(device);
+    source.into_iter().chain(destination).collect()
+}
+
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, String, String) {
+        let dir = TempDir::new().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let (source, destination) = (path("photo.jpg"), path("moved.jpg"));
+        fs::write(&source, b"the photo").unwrap();
+        (dir, source, destination)
+    }
+
+    #[test]
+    fn copy_with_the_wrong_hash_keeps_the_source() {
+        let (_dir, source, destination) = setup();
+        let error = copy_and_remove(&source, &destination, Some("0000000000000000"), &[], CopyEngine::Auto)
+            .unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{:#}", error);
+        assert_eq!(fs::read(&source).unwrap(), b"the photo");
+        assert!(!Path::new(&destination).exists());
+        assert!(!temp_path(Path::new(&destination)).exists());
+    }
+
+    #[test]
+    fn verified_copy_removes_the_source() {
+        let (_dir, source, destination) = setup();
+        let hash = hash_file(Path::new(&source)).unwrap();
+        let moved = copy_and_remove(&source, &destination, None, &[], CopyEngine::Auto).unwrap();
+        assert_eq!(moved.hash, Some(hash));
+        assert!(moved.source_kept.is_none());
+        assert!(!Path::new(&source).exists());
+        assert_eq!(fs::read(&destination).unwrap(), b"the photo");
+    }
+}
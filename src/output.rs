@@ -0,0 +1,228 @@
+use log::error;
+use serde::Serialize;
+use crate::args::OutputFormat;
+use crate::plan::{Collision, Operation, Plan};
+use crate::progress;
+use crate::report::RunReport;
+use crate::yee_file::YeeFile;
+
+/// Structured records on stdout for `--output json|jsonl`, so scripts don't have to
+/// parse the logs (which stay on stderr).
+///
+/// `jsonl` prints each record as soon as it is known: the collisions, then one record
+/// per operation and a final `stats` record, or an `error` record if the command failed.
+/// `json` collects the same records and prints them as one document at the end. `text`
+/// prints the plan on dry runs and nothing else.
+pub struct Output {
+    format: OutputFormat,
+    operations: Vec<OperationRecord>,
+    collisions: Vec<Collision>,
+    /// Set by `finish`, `None` for a dry run
+    finished: Option<Option<Stats>>,
+}
+
+/// What happened to one plan operation
+#[derive(Serialize, Clone, Debug)]
+pub struct OperationRecord {
+    /// Position of the operation in the plan
+    pub index: usize,
+    /// What was (or will be) done: the planned kind, or what conflict resolution chose
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub destination: String,
+    /// Why the operation is part of the plan
+    pub reason: String,
+    pub outcome: Outcome,
+    /// Why the operation was skipped or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The file being copied, moved or linked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<YeeFile>,
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// Part of a dry run's plan
+    Planned,
+    Succeeded,
+    Skipped,
+    Failed,
+    /// Done by an earlier attempt at a resumed run
+    AlreadyDone,
+}
+
+/// Totals of a run, the last record of the output
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    pub exit_code: i32,
+    pub interrupted: bool,
+    pub error_limit_reached: bool,
+    pub operations_done: usize,
+    pub operations_remaining: usize,
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub sources_kept: usize,
+    pub collisions: usize,
+    pub conflicts: usize,
+    pub preserve_failures: usize,
+}
+
+/// Why a command stopped early, the last record of its output
+#[derive(Serialize, Debug)]
+pub struct ErrorRecord {
+    pub message: String,
+    pub exit_code: i32,
+}
+
+/// One line of `jsonl` output, tagged with its type in `record`
+#[derive(Serialize)]
+#[serde(tag = "record", rename_all = "kebab-case")]
+enum Record<'a> {
+    Operation(&'a OperationRecord),
+    Collision(&'a Collision),
+    Stats(&'a Stats),
+    Error(&'a ErrorRecord),
+}
+
+/// The whole `json` output
+#[derive(Serialize)]
+struct Document<'a> {
+    dry_run: bool,
+    collisions: &'a [Collision],
+    operations: &'a [OperationRecord],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<&'a Stats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ErrorRecord>,
+}
+
+impl OperationRecord {
+    /// Record of a plan operation, with the planned action and destination
+    pub fn new(index: usize, operation: &Operation, outcome: Outcome) -> Self {
+        Self {
+            index,
+            action: operation.kind.name(),
+            source: operation.source.clone(),
+            destination: operation.destination.clone(),
+            reason: operation.reason.clone(),
+            outcome,
+            detail: None,
+            file: operation.file.clone(),
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+impl Stats {
+    pub fn new(report: &RunReport) -> Self {
+        Self {
+            run_id: report.run_id.clone(),
+            exit_code: report.exit_code(),
+            interrupted: report.interrupted,
+            error_limit_reached: report.error_limit_reached,
+            operations_done: report.operations_done,
+            operations_remaining: report.operations_remaining,
+            succeeded: report.succeeded,
+            skipped: report.skipped.len(),
+            failed: report.failures.len(),
+            sources_kept: report.sources_kept.len(),
+            collisions: report.collisions.len(),
+            conflicts: report.conflicts.len(),
+            preserve_failures: report.preserve_failures.len(),
+        }
+    }
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self { format, operations: Vec::new(), collisions: Vec::new(), finished: None }
+    }
+
+    /// The files the plan leaves out or renames because their destination was taken
+    pub fn collisions(&mut self, collisions: &[Collision]) {
+        match self.format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => self.collisions.extend_from_slice(collisions),
+            OutputFormat::Jsonl => {
+                for collision in collisions {
+                    print_line(&Record::Collision(collision));
+                }
+            }
+        }
+    }
+
+    /// Every operation of a plan that is not going to run
+    pub fn plan(&mut self, plan: &Plan) {
+        if self.format == OutputFormat::Text {
+            plan.print();
+            return;
+        }
+        for (index, operation) in plan.operations.iter().enumerate() {
+            self.operation(OperationRecord::new(index, operation, Outcome::Planned));
+        }
+    }
+
+    /// An operation of the plan that ran, or was skipped
+    pub fn operation(&mut self, record: OperationRecord) {
+        match self.format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => self.operations.push(record),
+            OutputFormat::Jsonl => print_line(&Record::Operation(&record)),
+        }
+    }
+
+    /// The plan is done, with the stats of the run unless it was a dry run
+    pub fn finish(&mut self, report: Option<&RunReport>) {
+        let stats = report.map(Stats::new);
+        if let (OutputFormat::Jsonl, Some(stats)) = (self.format, &stats) {
+            print_line(&Record::Stats(stats));
+        }
+        self.finished = Some(stats);
+    }
+
+    /// End the output once the command is over, with the error that stopped it if any.
+    /// A `json` document is only printed when the plan was finished or there is an error.
+    pub fn close(self, error: Option<ErrorRecord>) {
+        match self.format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => {
+                if self.finished.is_none() && error.is_none() {
+                    return;
+                }
+                let document = Document {
+                    dry_run: matches!(self.finished, Some(None)),
+                    collisions: &self.collisions,
+                    operations: &self.operations,
+                    stats: self.finished.as_ref().and_then(Option::as_ref),
+                    error: error.as_ref(),
+                };
+                match serde_json::to_string_pretty(&document) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => error!("Failed to write JSON output: {}", e),
+                }
+            }
+            OutputFormat::Jsonl => {
+                if let Some(error) = &error {
+                    print_line(&Record::Error(error));
+                }
+            }
+        }
+    }
+}
+
+fn print_line(record: &Record) {
+    match serde_json::to_string(record) {
+        Ok(line) => progress::suspend(|| println!("{}", line)),
+        Err(e) => error!("Failed to write JSON output: {}", e),
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use crate::args::{CollisionPolicy, YeeArgs};
+use crate::exit_code::ConfigError;
+use crate::yee_file::YeeFile;
+
+/// Everything a run will do, as a list of typed operations in execution order.
+///
+/// Building a plan has no side effects: it only looks at the processed files.
+/// A dry run prints or exports the plan, a real run hands it to the `Mover`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Plan {
+    pub operations: Vec<Operation>,
+    /// Files that mapped to a destination already taken by another file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collisions: Vec<Collision>,
+}
+
+/// A single step of a plan
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Operation {
+    pub kind: OpKind,
+    /// File being copied or moved; for a link in move mode, the source to remove afterwards
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub destination: String,
+    /// Why the operation is part of the plan
+    pub reason: String,
+    /// Existing destination file that a link points at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_to: Option<String>,
+    /// Hash of the source file, for copies, moves and links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Size of the source file in bytes
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub size: u64,
+    /// Contents of a metadata file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// The file being copied, moved or linked, with its final destination
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<YeeFile>,
+}
+
+/// Two source files that would be written to the same destination path
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Collision {
+    pub destination: String,
+    /// Source that keeps the destination
+    pub kept: String,
+    /// Source that was renamed or skipped
+    pub source: String,
+    /// New destination of the renamed file, if it was renamed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_to: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum OpKind {
+    Mkdir,
+    Copy,
+    Move,
+    Link,
+    WriteMetadata,
+}
+
+impl OpKind {
+    /// Name as it appears in printed and exported plans
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpKind::Mkdir => "mkdir",
+            OpKind::Copy => "copy",
+            OpKind::Move => "move",
+            OpKind::Link => "link",
+            OpKind::WriteMetadata => "write-metadata",
+        }
+    }
+}
+
+/// Copy of a file with its destination set to where the plan puts it, which differs
+/// from what `Meta` set for duplicates and renamed collisions
+fn placed(file: &YeeFile, destination: &str) -> YeeFile {
+    let mut file = file.clone();
+    if let Some(directory) = Path::new(destination).parent() {
+        file.destination_full_path = directory.to_string_lossy().to_string();
+    }
+    file
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+impl Operation {
+    fn new(kind: OpKind, destination: String, reason: String) -> Self {
+        Self {
+            kind,
+            source: None,
+            destination,
+            reason,
+            link_to: None,
+            hash: None,
+            size: 0,
+            content: None,
+            file: None,
+        }
+    }
+}
+
+/// Builds a `Plan` from files whose destinations were set by `Meta`.
+///
+/// Originals go to their destination folder, duplicates to the same layout under
+/// `_dupes`, and each folder gets its `.yeehaw` metadata. Files that would land on
+/// the same path are resolved by the `--on-collision` policy.
+pub struct Planner {
+    args: YeeArgs,
+}
+
+/// Where a file is going and why
+struct Placement {
+    /// Copy of the file with its final destination filename
+    file: YeeFile,
+    destination: PathBuf,
+    reason: String,
+}
+
+impl Planner {
+    pub fn new(args: YeeArgs) -> Self {
+        Self { args }
+    }
+
+    /// Plan the originals and the duplicates, each duplicate paired with the original it matched.
+    /// Fails if files collide and the collision policy is `error`.
+    pub fn build(&self, originals: &[YeeFile], duplicates: &[(&YeeFile, &YeeFile)]) -> anyhow::Result<Plan> {
+        // Work out every file's destination first, directories and metadata follow from them
+        let mut placements = Vec::new();
+        for file in originals {
+            placements.push(Placement {
+                file: file.clone(),
+                destination: PathBuf::from(file.destination_path()),
+                reason: "original".to_string(),
+            });
+        }
+        for (file, original) in duplicates {
+            let destination = self.dupe_directory(file).join(format!(
+                "{}.{}",
+                file.destination_filename, file.extension
+            ));
+            placements.push(Placement {
+                file: (*file).clone(),
+                destination,
+                reason: format!("duplicate of {}", original.source_path()),
+            });
+        }
+
+        let mut plan = Plan::default();
+        let placements = self.resolve_collisions(placements, &mut plan)?;
+
+        let directories: BTreeSet<PathBuf> = placements
+            .iter()
+            .filter_map(|placement| placement.destination.parent().map(Path::to_path_buf))
+            .collect();
+        for directory in &directories {
+            plan.operations.push(Operation::new(
+                OpKind::Mkdir,
+                directory.to_string_lossy().to_string(),
+                "destination folder".to_string(),
+            ));
+            plan.operations.push(Operation::new(
+                OpKind::Mkdir,
+                directory.join(".yeehaw").to_string_lossy().to_string(),
+                "metadata folder".to_string(),
+            ));
+        }
+
+        self.plan_metadata(&mut plan, &placements);
+
+        let transfer = if self.args.copy_mode { OpKind::Copy } else { OpKind::Move };
+        let mut linked: HashMap<(u64, u64), String> = HashMap::new();
+        for Placement { file, destination, reason } in &placements {
+            let destination = destination.to_string_lossy().to_string();
+            let link_id = file.link_id().filter(|_| self.args.preserve_links);
+
+            let mut operation = match link_id.and_then(|id| linked.get(&id)) {
+                Some(target) => {
+                    let mut operation =
+                        Operation::new(OpKind::Link, destination.clone(), format!("hardlink, {}", reason));
+                    operation.link_to = Some(target.clone());
+                    if !self.args.copy_mode {
+                        operation.source = Some(file.source_path());
+                    }
+                    operation
+                }
+                None => {
+                    let mut operation = Operation::new(transfer, destination.clone(), reason.clone());
+                    operation.source = Some(file.source_path());
+                    operation
+                }
+            };
+            operation.hash = file.hash.clone();
+            operation.size = file.size;
+            operation.file = Some(placed(file, &destination));
+            plan.operations.push(operation);
+
+            if let Some(link_id) = link_id {
+                linked.entry(link_id).or_insert(destination);
+            }
+        }
+
+        debug!("Planned {} operations", plan.operations.len());
+        Ok(plan)
+    }
+
+    /// Give every file a destination of its own. The first file to claim a path keeps
+    /// it, later ones are renamed or skipped according to the collision policy.
+    fn resolve_collisions(&self, placements: Vec<Placement>, plan: &mut Plan) -> anyhow::Result<Vec<Placement>> {
+        let mut taken: HashMap<PathBuf, String> = HashMap::new();
+        let mut resolved = Vec::with_capacity(placements.len());
+
+        for mut placement in placements {
+            let Some(kept) = taken.get(&placement.destination).cloned() else {
+                taken.insert(placement.destination.clone(), placement.file.source_path());
+                resolved.push(placement);
+                continue;
+            };
+
+            let mut collision = Collision {
+                destination: placement.destination.to_string_lossy().to_string(),
+                kept,
+                source: placement.file.source_path(),
+                renamed_to: None,
+            };
+
+            match self.args.on_collision {
+                CollisionPolicy::Error | CollisionPolicy::Skip => {
+                    debug!(
+                        "Collision: skipping {}, {} is already planned for {}",
+                        collision.source, collision.kept, collision.destination
+                    );
+                }
+                CollisionPolicy::Suffix | CollisionPolicy::HashSuffix => {
+                    let filename = self.free_filename(&placement, &taken);
+                    placement
+                        .destination
+                        .set_file_name(format!("{}.{}", filename, placement.file.extension));
+                    placement.file.destination_filename = filename;
+                    debug!("Collision: renaming {} to {}", collision.source, placement.destination.display());
+
+                    collision.renamed_to = Some(placement.destination.to_string_lossy().to_string());
+                    taken.insert(placement.destination.clone(), placement.file.source_path());
+                    resolved.push(placement);
+                }
+            }
+            plan.collisions.push(collision);
+        }
+
+        if self.args.on_collision == CollisionPolicy::Error && !plan.collisions.is_empty() {
+            let first = &plan.collisions[0];
+            return Err(ConfigError(format!(
+                "{} files collide with another file's destination, e.g. {} and {} both map to {}",
+                plan.collisions.len(),
+                first.kept,
+                first.source,
+                first.destination
+            ))
+            .into());
+        }
+        Ok(resolved)
+    }
+
+    /// A destination filename (without extension) next to the colliding one that no
+    /// other file has claimed: `name_1`, `name_2`, ... or `name_<short hash>`
+    fn free_filename(&self, placement: &Placement, taken: &HashMap<PathBuf, String>) -> String {
+        let file = &placement.file;
+        let mut base = file.destination_filename.clone();
+        if self.args.on_collision == CollisionPolicy::HashSuffix {
+            if let Some(hash) = &file.hash {
+                base = format!("{}_{}", base, &hash[..hash.len().min(8)]);
+            }
+        }
+
+        let is_free = |name: &str| {
+            !taken.contains_key(&placement.destination.with_file_name(format!("{}.{}", name, file.extension)))
+        };
+        if base != file.destination_filename && is_free(&base) {
+            return base;
+        }
+        (1..)
+            .map(|n| format!("{}_{}", base, n))
+            .find(|name| is_free(name))
+            .unwrap_or(base)
+    }
+
+    /// Where a duplicate goes: its destination folder, mirrored under `_dupes`
+    pub fn dupe_directory(&self, file: &YeeFile) -> PathBuf {
+        let dest_root = Path::new(&self.args.destination_dir);
+        let relative_dest_path = Path::new(&file.destination_full_path)
+            .strip_prefix(dest_root)
+            .unwrap_or(Path::new(&file.destination_local_path));
+        dest_root.join("_dupes").join(relative_dest_path)
+    }
+
+    /// One YAML file per file plus a summary per group, in the `.yeehaw` folder
+    /// next to where the files land
+    fn plan_metadata(&self, plan: &mut Plan, placements: &[Placement]) {
+        #[derive(Serialize)]
+        struct GroupSummary {
+            group_id: String,
+            file_count: usize,
+            files: Vec<String>,
+        }
+
+        let mut groups: BTreeMap<(PathBuf, String), Vec<&YeeFile>> = BTreeMap::new();
+        for Placement { file, destination, .. } in placements {
+            if let Some(directory) = destination.parent() {
+                groups
+                    .entry((directory.join(".yeehaw"), file.group_id.clone()))
+                    .or_default()
+                    .push(file);
+            }
+        }
+
+        for ((yeehaw_dir, group_id), files) in groups {
+            for file in &files {
+                let metadata_path = yeehaw_dir.join(format!(
+                    "{}_{}_{}.yaml",
+                    group_id, file.destination_filename, file.extension
+                ));
+                let mut operation = Operation::new(
+                    OpKind::WriteMetadata,
+                    metadata_path.to_string_lossy().to_string(),
+                    format!("metadata for {}", file.source_path()),
+                );
+                operation.content = Some(serde_yaml::to_string(file).unwrap_or_default());
+                plan.operations.push(operation);
+            }
+
+            let summary = GroupSummary {
+                group_id: group_id.clone(),
+                file_count: files.len(),
+                files: files
+                    .iter()
+                    .map(|f| format!("{}.{}", f.destination_filename, f.extension))
+                    .collect(),
+            };
+            let mut operation = Operation::new(
+                OpKind::WriteMetadata,
+                yeehaw_dir.join(format!("{}_summary.yaml", group_id)).to_string_lossy().to_string(),
+                format!("summary for group {}", group_id),
+            );
+            operation.content = Some(serde_yaml::to_string(&summary).unwrap_or_default());
+            plan.operations.push(operation);
+        }
+    }
+}
+
+impl Plan {
+    /// Count of operations of the given kind
+    pub fn count(&self, kind: OpKind) -> usize {
+        self.operations.iter().filter(|op| op.kind == kind).count()
+    }
+
+    /// Print the plan to stdout, one operation per line
+    pub fn print(&self) {
+        for operation in &self.operations {
+            let kind = operation.kind.name();
+            match (&operation.source, &operation.link_to) {
+                (_, Some(target)) => println!(
+                    "{:<14} {} -> {}  ({})",
+                    kind, operation.destination, target, operation.reason
+                ),
+                (Some(source), None) => println!(
+                    "{:<14} {} -> {}  ({})",
+                    kind, source, operation.destination, operation.reason
+                ),
+                (None, None) => println!("{:<14} {}  ({})", kind, operation.destination, operation.reason),
+            }
+        }
+    }
+
+    /// Read a plan written by `write`
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = fs::File::open(path)?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    /// Write the plan as YAML to the given path
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        let yaml_content = serde_yaml::to_string(self)?;
+        let mut file = fs::File::create(path)?;
+        file.write_all(yaml_content.as_bytes())?;
+        file.sync_all()?;
+        info!("Wrote plan with {} operations to {}", self.operations.len(), path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn planner(on_collision: &str) -> Planner {
+        Planner::new(YeeArgs::parse_from(["yee-haw", "-d", "/d", "--on-collision", on_collision]))
+    }
+
+    /// A file from `/s/<source>` planned for `/d/<destination>`
+    fn placement(source: &str, destination: &str, hash: &str) -> Placement {
+        let mut file = YeeFile::from_path(Path::new("/s"), &Path::new("/s").join(source)).unwrap();
+        file.destination_filename = Path::new(destination).file_stem().unwrap().to_string_lossy().to_string();
+        file.hash = Some(hash.to_string());
+        Placement { file, destination: Path::new("/d").join(destination), reason: "original".to_string() }
+    }
+
+    /// Two files after the first one for `x.jpg`, with `x_1.jpg` already taken
+    fn resolve(planner: &Planner) -> (anyhow::Result<Vec<String>>, Plan) {
+        let placements = vec![
+            placement("a/x.jpg", "x.jpg", "1111111111111111"),
+            placement("x_1.jpg", "x_1.jpg", "2222222222222222"),
+            placement("b/x.jpg", "x.jpg", "3333333333333333"),
+            placement("c/x.jpg", "x.jpg", "3333333333333333"),
+        ];
+        let mut plan = Plan::default();
+        let resolved = planner.resolve_collisions(placements, &mut plan).map(|resolved| {
+            resolved.iter().map(|placement| placement.destination.to_string_lossy().to_string()).collect()
+        });
+        (resolved, plan)
+    }
+
+    #[test]
+    fn suffix_renames_to_the_next_free_number() {
+        let (resolved, plan) = resolve(&planner("suffix"));
+        assert_eq!(resolved.unwrap(), ["/d/x.jpg", "/d/x_1.jpg", "/d/x_2.jpg", "/d/x_3.jpg"]);
+        assert_eq!(plan.collisions.len(), 2);
+        assert_eq!(plan.collisions[0].kept, "/s/a/x.jpg");
+        assert_eq!(plan.collisions[0].source, "/s/b/x.jpg");
+        assert_eq!(plan.collisions[0].renamed_to.as_deref(), Some("/d/x_2.jpg"));
+    }
+
+    #[test]
+    fn hash_suffix_adds_the_hash_then_a_number() {
+        let (resolved, _) = resolve(&planner("hash-suffix"));
+        assert_eq!(
+            resolved.unwrap(),
+            ["/d/x.jpg", "/d/x_1.jpg", "/d/x_33333333.jpg", "/d/x_33333333_1.jpg"]
+        );
+    }
+
+    #[test]
+    fn skip_and_error_leave_colliding_files_out() {
+        let (resolved, plan) = resolve(&planner("skip"));
+        assert_eq!(resolved.unwrap(), ["/d/x.jpg", "/d/x_1.jpg"]);
+        assert_eq!(plan.collisions.len(), 2);
+        assert!(plan.collisions.iter().all(|collision| collision.renamed_to.is_none()));
+
+        let (resolved, _) = resolve(&planner("error"));
+        let error = resolved.unwrap_err();
+        assert!(error.downcast_ref::<ConfigError>().is_some());
+        assert!(error.to_string().starts_with("2 files collide"));
+    }
+}
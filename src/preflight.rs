@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use indicatif::HumanBytes;
+use log::{debug, error};
+use crate::args::YeeArgs;
+use crate::exit_code::PreflightError;
+use crate::plan::{OpKind, Operation, Plan};
+
+/// Sources listed by name per problem, the rest are only counted
+const LISTED: usize = 10;
+
+/// Checks a plan before it runs, so it doesn't stop halfway through: that each
+/// destination filesystem has room for what the plan writes to it plus `--reserve`,
+/// that every folder written to can be written, and that every source can be read
+/// and, when moving, deleted.
+///
+/// Everything is checked before anything is reported, so one run shows all there
+/// is to fix.
+pub struct Preflight<'a> {
+    args: &'a YeeArgs,
+    /// The nearest existing folder and its filesystem, per destination folder
+    folders: HashMap<PathBuf, Option<(PathBuf, u64)>>,
+    /// Bytes to write per filesystem, with an existing folder on it
+    needed: BTreeMap<u64, (PathBuf, u64)>,
+    /// Whether files can be created in and removed from each existing folder checked
+    writable: HashMap<PathBuf, bool>,
+    /// Folders already reported as not writable
+    reported: HashSet<PathBuf>,
+    problems: Vec<String>,
+    unreadable: Vec<String>,
+    undeletable: Vec<String>,
+}
+
+impl<'a> Preflight<'a> {
+    pub fn new(args: &'a YeeArgs) -> Self {
+        Self {
+            args,
+            folders: HashMap::new(),
+            needed: BTreeMap::new(),
+            writable: HashMap::new(),
+            reported: HashSet::new(),
+            problems: Vec::new(),
+            unreadable: Vec::new(),
+            undeletable: Vec::new(),
+        }
+    }
+
+    /// Check the operations of `plan` that aren't `done`, logging every problem found.
+    /// Fails with a `PreflightError` if there are any.
+    pub fn check(mut self, plan: &Plan, done: impl Fn(usize) -> bool) -> anyhow::Result<()> {
+        // The journal and run reports go in the destination itself
+        self.destination_folder(Path::new(&self.args.destination_dir));
+        for (index, operation) in plan.operations.iter().enumerate() {
+            if !done(index) {
+                self.operation(operation);
+            }
+        }
+        self.check_space();
+
+        let problems = self.problems();
+        if problems.is_empty() {
+            debug!("Preflight checks passed");
+            return Ok(());
+        }
+        for problem in &problems {
+            error!("Preflight: {}", problem);
+        }
+        Err(PreflightError(problems.len()).into())
+    }
+
+    fn operation(&mut self, operation: &Operation) {
+        let destination = Path::new(&operation.destination);
+        let folder = match operation.kind {
+            OpKind::Mkdir => destination,
+            _ => destination.parent().unwrap_or(Path::new(".")),
+        };
+        let Some((existing, device)) = self.destination_folder(folder) else {
+            return;
+        };
+
+        // Within a filesystem a move is a rename, which needs no room and doesn't read the file
+        let renamed = operation.kind == OpKind::Move && source_device(operation) == Some(device);
+        let bytes = match operation.kind {
+            OpKind::Copy | OpKind::Move if !renamed => operation.size,
+            OpKind::WriteMetadata => operation.content.as_ref().map_or(0, |content| content.len() as u64),
+            _ => 0,
+        };
+        if bytes > 0 {
+            self.needed.entry(device).or_insert_with(|| (existing, 0)).1 += bytes;
+        }
+
+        let Some(source) = operation.source.as_deref().map(Path::new) else {
+            return;
+        };
+        if matches!(operation.kind, OpKind::Copy | OpKind::Move) && !renamed && !access::readable(source) {
+            self.unreadable.push(source.display().to_string());
+        }
+        // A link in move mode removes its source too
+        if matches!(operation.kind, OpKind::Move | OpKind::Link) && !self.removable(source) {
+            self.undeletable.push(source.display().to_string());
+        }
+    }
+
+    /// The nearest existing folder of a destination folder and its filesystem,
+    /// checking the folder can be written the first time it is seen
+    fn destination_folder(&mut self, folder: &Path) -> Option<(PathBuf, u64)> {
+        if let Some(found) = self.folders.get(folder) {
+            return found.clone();
+        }
+        let found = folder
+            .ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .find_map(|ancestor| fs::metadata(ancestor).ok().map(|metadata| (ancestor.to_path_buf(), metadata)));
+        let found = match found {
+            Some((existing, metadata)) if metadata.is_dir() => {
+                if !self.writable(&existing) && self.reported.insert(existing.clone()) {
+                    self.problems.push(format!("{}: no permission to create files or folders in it", existing.display()));
+                }
+                Some((existing.clone(), access::device(&metadata)))
+            }
+            Some((existing, _)) => {
+                self.problems.push(format!("{}: is in the way of {}, it is not a folder", existing.display(), folder.display()));
+                None
+            }
+            None => None,
+        };
+        self.folders.insert(folder.to_path_buf(), found.clone());
+        found
+    }
+
+    /// Whether a source file can be deleted from its folder
+    fn removable(&mut self, source: &Path) -> bool {
+        let Some(parent) = source.parent() else {
+            return false;
+        };
+        let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        self.writable(parent) && access::may_unlink(parent, source)
+    }
+
+    fn writable(&mut self, folder: &Path) -> bool {
+        *self.writable.entry(folder.to_path_buf()).or_insert_with(|| access::writable(folder))
+    }
+
+    /// Compare what the plan writes to each filesystem with the space available on it
+    fn check_space(&mut self) {
+        for (folder, bytes) in self.needed.values() {
+            let Some(available) = access::available_space(folder) else {
+                debug!("Can't tell how much space is left at {}, not checking it", folder.display());
+                continue;
+            };
+            let needed = bytes.saturating_add(self.args.reserve);
+            if needed > available {
+                self.problems.push(format!(
+                    "{}: the plan writes {} to this filesystem and keeps {} free, but only {} is available",
+                    folder.display(),
+                    HumanBytes(*bytes),
+                    HumanBytes(self.args.reserve),
+                    HumanBytes(available)
+                ));
+            }
+        }
+    }
+
+    /// Every problem found, with the sources listed up to `LISTED` at a time
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.problems.clone();
+        for (sources, problem) in [(&self.unreadable, "can't be read"), (&self.undeletable, "can't be deleted")] {
+            problems.extend(sources.iter().take(LISTED).map(|source| format!("{}: {}", source, problem)));
+            if sources.len() > LISTED {
+                problems.push(format!("{} more sources {}", sources.len() - LISTED, problem));
+            }
+        }
+        problems
+    }
+}
+
+/// The filesystem a move's source is on
+fn source_device(operation: &Operation) -> Option<u64> {
+    operation
+        .file
+        .as_ref()
+        .and_then(|file| file.device)
+        .or_else(|| fs::metadata(operation.source.as_deref()?).ok().map(|metadata| access::device(&metadata)))
+}
+
+#[cfg(unix)]
+mod access {
+    use std::ffi::CString;
+    use std::fs::{self, Metadata};
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    /// Whether this process may access `path` with `mode`, as the kernel would decide
+    /// (effective user, ACLs, read-only mounts)
+    fn allowed(path: &Path, mode: libc::c_int) -> bool {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) == 0 }
+    }
+
+    pub fn readable(path: &Path) -> bool {
+        allowed(path, libc::R_OK)
+    }
+
+    /// Whether files can be created in and removed from a folder
+    pub fn writable(folder: &Path) -> bool {
+        allowed(folder, libc::W_OK | libc::X_OK)
+    }
+
+    /// In a sticky folder such as /tmp, only the owner of a file or of the folder may remove it
+    #[allow(clippy::unnecessary_cast)] // mode_t is narrower on some platforms
+    pub fn may_unlink(folder: &Path, file: &Path) -> bool {
+        let (Ok(folder), Ok(file)) = (fs::metadata(folder), fs::symlink_metadata(file)) else {
+            return true;
+        };
+        let user = unsafe { libc::geteuid() };
+        folder.mode() & libc::S_ISVTX as u32 == 0 || user == 0 || user == file.uid() || user == folder.uid()
+    }
+
+    pub fn device(metadata: &Metadata) -> u64 {
+        metadata.dev()
+    }
+
+    /// Bytes an unprivileged user can still write to the filesystem holding `path`
+    #[allow(clippy::unnecessary_cast)] // the statvfs fields are narrower on some platforms
+    pub fn available_space(path: &Path) -> Option<u64> {
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let stats = unsafe { stats.assume_init() };
+        Some((stats.f_bavail as u64).saturating_mul(stats.f_frsize as u64))
+    }
+}
+
+#[cfg(not(unix))]
+mod access {
+    use std::fs::{self, File, Metadata};
+    use std::path::Path;
+
+    pub fn readable(path: &Path) -> bool {
+        File::open(path).is_ok()
+    }
+
+    pub fn writable(folder: &Path) -> bool {
+        fs::metadata(folder).is_ok_and(|metadata| !metadata.permissions().readonly())
+    }
+
+    pub fn may_unlink(_folder: &Path, _file: &Path) -> bool {
+        true
+    }
+
+    pub fn device(_metadata: &Metadata) -> u64 {
+        0
+    }
+
+    pub fn available_space(_path: &Path) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::path::Path;
+use filetime::FileTime;
+use log::trace;
+use crate::args::Preserve;
+use crate::report::PreserveFailure;
+
+/// Prefix of the extended attributes that hold POSIX ACLs
+#[cfg(unix)]
+const ACL_PREFIX: &str = "system.posix_acl_";
+
+/// Copy the selected attributes of `source` onto `destination`, returning anything
+/// that could not be preserved.
+///
+/// Ownership goes first since changing it can clear permission bits, ACLs come after
+/// the mode since they refine it, and times go last since setting the other
+/// attributes can touch them.
+pub fn apply(source: &Path, destination: &Path, what: &[Preserve]) -> Vec<PreserveFailure> {
+    let mut failures = Vec::new();
+    let metadata = match fs::metadata(source) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            failures.push(failure(destination, "all", e.to_string()));
+            return failures;
+        }
+    };
+
+    for attribute in [Preserve::Owner, Preserve::Mode, Preserve::Xattr, Preserve::Acl, Preserve::Times] {
+        if !what.contains(&attribute) {
+            continue;
+        }
+        let result = match attribute {
+            Preserve::Owner => preserve_owner(&metadata, destination),
+            Preserve::Xattr => preserve_xattrs(source, destination, false),
+            Preserve::Acl => preserve_xattrs(source, destination, true),
+            Preserve::Mode => fs::set_permissions(destination, metadata.permissions()).map_err(Into::into),
+            Preserve::Times => filetime::set_file_times(
+                destination,
+                FileTime::from_last_access_time(&metadata),
+                FileTime::from_last_modification_time(&metadata),
+            )
+            .map_err(Into::into),
+        };
+        match result {
+            Ok(()) => trace!("Preserved {} on {}", name(attribute), destination.display()),
+            Err(e) => failures.push(failure(destination, name(attribute), e.to_string())),
+        }
+    }
+    failures
+}
+
+/// Name of the attribute as passed to `--preserve`
+fn name(attribute: Preserve) -> &'static str {
+    match attribute {
+        Preserve::Times => "times",
+        Preserve::Mode => "mode",
+        Preserve::Owner => "owner",
+        Preserve::Xattr => "xattr",
+        Preserve::Acl => "acl",
+    }
+}
+
+fn failure(destination: &Path, attribute: &str, error: String) -> PreserveFailure {
+    PreserveFailure {
+        destination: destination.to_string_lossy().to_string(),
+        attribute: attribute.to_string(),
+        error,
+    }
+}
+
+#[cfg(unix)]
+fn preserve_owner(metadata: &fs::Metadata, destination: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let current = fs::metadata(destination)?;
+    if current.uid() == metadata.uid() && current.gid() == metadata.gid() {
+        return Ok(());
+    }
+    std::os::unix::fs::chown(destination, Some(metadata.uid()), Some(metadata.gid()))?;
+    Ok(())
+}
+
+/// Copy extended attributes: the POSIX ACLs when `acls` is set, every other one otherwise
+#[cfg(unix)]
+fn preserve_xattrs(source: &Path, destination: &Path, acls: bool) -> anyhow::Result<()> {
+    let mut errors = Vec::new();
+    for attribute in xattr::list(source)? {
+        let attribute_name = attribute.to_string_lossy();
+        if attribute_name.starts_with(ACL_PREFIX) != acls {
+            continue;
+        }
+        let Some(value) = xattr::get(source, &attribute)? else {
+            continue;
+        };
+        if let Err(e) = xattr::set(destination, &attribute, &value) {
+            errors.push(format!("{}: {}", attribute_name, e));
+        }
+    }
+
+    if !errors.is_empty() {
+        anyhow::bail!(errors.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn preserve_owner(_metadata: &fs::Metadata, _destination: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("not supported on this platform")
+}
+
+#[cfg(not(unix))]
+fn preserve_xattrs(_source: &Path, _destination: &Path, _acls: bool) -> anyhow::Result<()> {
+    anyhow::bail!("not supported on this platform")
+}
@@ -0,0 +1,181 @@
+use std::io::IsTerminal;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+
+/// How often plain progress lines are printed when there is no terminal to draw on
+const LINE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where the progress bars are drawn. The logger prints through it too, so log lines
+/// appear above the bar instead of tearing it.
+static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+/// How progress is shown, set once the arguments are parsed
+static DISPLAY: OnceLock<Display> = OnceLock::new();
+/// The phase currently reporting progress
+static PHASE: Mutex<Option<Phase>> = Mutex::new(None);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Display {
+    /// A live bar on stderr
+    Bar,
+    /// A plain line on stderr every `LINE_INTERVAL`, for logs and cron mail
+    Lines,
+    /// Nothing, with `--quiet`
+    Hidden,
+}
+
+/// Progress of one stage of the pipeline: scanning, hashing or moving
+struct Phase {
+    name: &'static str,
+    bar: ProgressBar,
+    files: u64,
+    total_files: Option<u64>,
+    current: String,
+    last_line: Instant,
+}
+
+/// Ends the phase it was returned for when dropped
+pub struct PhaseGuard;
+
+impl Drop for PhaseGuard {
+    fn drop(&mut self) {
+        let Some(phase) = lock().take() else {
+            return;
+        };
+        phase.bar.finish_and_clear();
+        if display() == Display::Lines {
+            phase.print_line();
+        }
+    }
+}
+
+/// Install the logger, routed through the progress display. `RUST_LOG` works as before.
+pub fn init_logger() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let multi = MULTI.get_or_init(MultiProgress::new).clone();
+    if let Err(e) = LogWrapper::new(multi, builder.build()).try_init() {
+        eprintln!("Failed to set up logging: {}", e);
+    }
+}
+
+/// Pick how progress is shown: a bar when both stdout and stderr are terminals,
+/// plain lines otherwise, and nothing with `quiet`
+pub fn configure(quiet: bool) {
+    let display = if quiet {
+        Display::Hidden
+    } else if std::io::stdout().is_terminal() && std::io::stderr().is_terminal() {
+        Display::Bar
+    } else {
+        Display::Lines
+    };
+    let _ = DISPLAY.set(display);
+    if display != Display::Bar {
+        multi().set_draw_target(ProgressDrawTarget::hidden());
+    }
+}
+
+/// Start showing the progress of a phase. Totals that aren't known up front (while
+/// scanning) are left out, and the display shows a spinner instead of a bar and ETA.
+pub fn start(name: &'static str, total_files: Option<u64>, total_bytes: Option<u64>) -> PhaseGuard {
+    let bar = multi().add(ProgressBar::with_draw_target(total_bytes, ProgressDrawTarget::hidden()));
+    if display() == Display::Bar {
+        let template = if total_bytes.is_some() {
+            "{prefix:>8} [{bar:20}] {binary_bytes}/{binary_total_bytes} {binary_bytes_per_sec} ETA {eta} {wide_msg}"
+        } else {
+            "{prefix:>8} {spinner} {binary_bytes} {binary_bytes_per_sec} {wide_msg}"
+        };
+        if let Ok(style) = ProgressStyle::with_template(template) {
+            bar.set_style(style.progress_chars("=> "));
+        }
+        bar.enable_steady_tick(Duration::from_millis(200));
+    }
+    bar.set_prefix(name);
+
+    let mut phase = Phase {
+        name,
+        bar,
+        files: 0,
+        total_files,
+        current: String::new(),
+        last_line: Instant::now(),
+    };
+    phase.update();
+    if let Some(previous) = lock().replace(phase) {
+        previous.bar.finish_and_clear();
+    }
+    PhaseGuard
+}
+
+/// Show the file the current phase is working on
+pub fn file(path: &str) {
+    if let Some(phase) = lock().as_mut() {
+        phase.current = path.to_string();
+        phase.update();
+    }
+}
+
+/// Count a file of `bytes` as done by the current phase
+pub fn advance(bytes: u64) {
+    if let Some(phase) = lock().as_mut() {
+        phase.files += 1;
+        phase.bar.inc(bytes);
+        phase.update();
+    }
+}
+
+/// Run `f` with the progress bar hidden, for anything else printed to the terminal
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    multi().suspend(f)
+}
+
+impl Phase {
+    /// Refresh the bar's message, or print a line if one is due
+    fn update(&mut self) {
+        match display() {
+            Display::Bar => self.bar.set_message(format!("{} files  {}", self.file_count(), self.current)),
+            Display::Lines if self.last_line.elapsed() >= LINE_INTERVAL => {
+                self.print_line();
+                self.last_line = Instant::now();
+            }
+            Display::Lines | Display::Hidden => {}
+        }
+    }
+
+    fn print_line(&self) {
+        let mut line = format!("{}: {} files, {}", self.name, self.file_count(), HumanBytes(self.bar.position()));
+        if let Some(total) = self.bar.length() {
+            line.push_str(&format!("/{}", HumanBytes(total)));
+        }
+        line.push_str(&format!(", {}/s", HumanBytes(self.bar.per_sec() as u64)));
+        if self.bar.length().is_some() && self.bar.position() > 0 {
+            line.push_str(&format!(", ETA {}", HumanDuration(self.bar.eta())));
+        }
+        if !self.current.is_empty() {
+            line.push_str(&format!(", {}", self.current));
+        }
+        eprintln!("{}", line);
+    }
+
+    fn file_count(&self) -> String {
+        match self.total_files {
+            Some(total) => format!("{}/{}", self.files, total),
+            None => self.files.to_string(),
+        }
+    }
+}
+
+fn multi() -> &'static MultiProgress {
+    MULTI.get_or_init(MultiProgress::new)
+}
+
+fn display() -> Display {
+    DISPLAY.get().copied().unwrap_or(Display::Hidden)
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<Phase>> {
+    PHASE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
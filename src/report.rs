@@ -0,0 +1,470 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use serde::Serialize;
+use crate::exit_code;
+use crate::plan::{Collision, Plan};
+use crate::similar_images::ImageCluster;
+use crate::similar_text::TextCluster;
+use crate::store::Store;
+use crate::tree::DirTree;
+use crate::yee_file::YeeFile;
+
+/// Summary of everything that was found to be duplicated during a run.
+///
+/// Collects the exact file matches from the `Store` and, when enabled, the
+/// directory-level matches from the `DirTree`. Logged at the end of analysis and
+/// optionally written to a YAML file with `--report`.
+#[derive(Serialize, Default)]
+pub struct DupesReport {
+    /// Bytes that removing the duplicates would free
+    pub wasted_bytes: u64,
+    pub file_clusters: Vec<FileCluster>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub already_linked: Vec<Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub identical_dirs: Vec<DirCluster>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub superset_dirs: Vec<DirSuperset>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub similar_dirs: Vec<DirSimilar>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub similar_images: Vec<ImageSimilar>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub similar_text: Vec<TextSimilar>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub empty_files: Vec<String>,
+}
+
+/// Files with identical content: the copy that is kept and the ones treated as duplicates
+#[derive(Serialize)]
+pub struct FileCluster {
+    pub hash: String,
+    pub kept: String,
+    pub duplicates: Vec<String>,
+}
+
+/// Directories whose whole subtrees are identical
+#[derive(Serialize)]
+pub struct DirCluster {
+    pub hash: String,
+    pub file_count: usize,
+    pub dirs: Vec<String>,
+}
+
+/// A directory whose files all exist under another directory
+#[derive(Serialize)]
+pub struct DirSuperset {
+    pub superset: String,
+    pub subset: String,
+    pub file_count: usize,
+}
+
+/// Two directories that share most of their files, with what each is missing
+#[derive(Serialize)]
+pub struct DirSimilar {
+    pub left: String,
+    pub right: String,
+    pub similarity: f64,
+    pub only_in_left: Vec<String>,
+    pub only_in_right: Vec<String>,
+}
+
+/// Visually similar images: the highest resolution copy that is kept and the others
+#[derive(Serialize)]
+pub struct ImageSimilar {
+    pub kept: String,
+    pub kept_resolution: String,
+    pub similar: Vec<SimilarImage>,
+}
+
+#[derive(Serialize)]
+pub struct SimilarImage {
+    pub path: String,
+    pub resolution: String,
+    pub distance: u32,
+}
+
+/// Near-identical text files, with their similarity to the first file listed
+#[derive(Serialize)]
+pub struct TextSimilar {
+    pub files: Vec<SimilarText>,
+}
+
+#[derive(Serialize)]
+pub struct SimilarText {
+    pub path: String,
+    pub similarity: f64,
+}
+
+impl DupesReport {
+    /// Add the store's exact duplicate clusters, hardlinked files and wasted space
+    pub fn add_file_clusters(&mut self, store: &Store) {
+        self.wasted_bytes = store.wasted_bytes();
+        self.already_linked = store
+            .linked_groups()
+            .into_iter()
+            .map(|group| group.into_iter().map(YeeFile::source_path).collect())
+            .collect();
+
+        self.file_clusters = store
+            .clusters()
+            .into_iter()
+            .map(|cluster| {
+                let original = &store.originals()[cluster.original];
+                FileCluster {
+                    hash: original
+                        .dedup_key
+                        .clone()
+                        .or_else(|| original.hash.clone())
+                        .unwrap_or_default(),
+                    kept: original.source_path(),
+                    duplicates: cluster
+                        .duplicates
+                        .iter()
+                        .map(|&i| store.duplicates()[i].source_path())
+                        .collect(),
+                }
+            })
+            .collect();
+    }
+
+    /// Add every empty file, as they are kept out of duplicate detection
+    pub fn add_empty_files(&mut self, files: &[YeeFile]) {
+        self.empty_files = files
+            .iter()
+            .filter(|f| f.size == 0)
+            .map(YeeFile::source_path)
+            .collect();
+    }
+
+    /// Add identical and contained directories found by the directory analysis
+    pub fn add_dir_tree(&mut self, tree: &DirTree) {
+        self.identical_dirs = tree
+            .identical()
+            .into_iter()
+            .map(|group| DirCluster {
+                hash: group.hash,
+                file_count: group.file_count,
+                dirs: group.dirs.iter().map(|d| d.to_string_lossy().to_string()).collect(),
+            })
+            .collect();
+
+        self.superset_dirs = tree
+            .supersets()
+            .into_iter()
+            .map(|pair| DirSuperset {
+                superset: pair.superset.to_string_lossy().to_string(),
+                subset: pair.subset.to_string_lossy().to_string(),
+                file_count: pair.file_count,
+            })
+            .collect();
+    }
+
+    /// Add directory pairs whose contents overlap by at least `threshold`
+    pub fn add_similar_dirs(&mut self, tree: &DirTree, threshold: f64) {
+        let paths = |paths: &[PathBuf]| -> Vec<String> {
+            paths.iter().map(|p| p.to_string_lossy().to_string()).collect()
+        };
+        self.similar_dirs = tree
+            .similar(threshold)
+            .into_iter()
+            .map(|pair| DirSimilar {
+                left: pair.left.to_string_lossy().to_string(),
+                right: pair.right.to_string_lossy().to_string(),
+                similarity: pair.similarity,
+                only_in_left: paths(&pair.only_in_left),
+                only_in_right: paths(&pair.only_in_right),
+            })
+            .collect();
+    }
+
+    /// Add clusters of visually similar images
+    pub fn add_similar_images(&mut self, clusters: &[ImageCluster]) {
+        self.similar_images = clusters
+            .iter()
+            .map(|cluster| {
+                let kept = &cluster.members[cluster.kept];
+                ImageSimilar {
+                    kept: kept.path.clone(),
+                    kept_resolution: format!("{}x{}", kept.width, kept.height),
+                    similar: cluster
+                        .members
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| *i != cluster.kept)
+                        .map(|(_, m)| SimilarImage {
+                            path: m.path.clone(),
+                            resolution: format!("{}x{}", m.width, m.height),
+                            distance: m.distance,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+    }
+
+    /// Add clusters of near-identical text files
+    pub fn add_similar_text(&mut self, clusters: &[TextCluster]) {
+        self.similar_text = clusters
+            .iter()
+            .map(|cluster| TextSimilar {
+                files: cluster
+                    .members
+                    .iter()
+                    .map(|m| SimilarText {
+                        path: m.path.clone(),
+                        similarity: m.similarity,
+                    })
+                    .collect(),
+            })
+            .collect();
+    }
+
+    /// Log a short summary of the report
+    pub fn log_summary(&self) {
+        info!(
+            "Duplicate report: {} file clusters wasting {} bytes",
+            self.file_clusters.len(),
+            self.wasted_bytes
+        );
+        for group in &self.already_linked {
+            info!("Already linked: {}", group.join(", "));
+        }
+
+        for group in &self.identical_dirs {
+            info!(
+                "Identical directories ({} files): {}",
+                group.file_count,
+                group.dirs.join(", ")
+            );
+        }
+        for pair in &self.superset_dirs {
+            info!(
+                "Directory {} ({} files) is contained in {}",
+                pair.subset, pair.file_count, pair.superset
+            );
+        }
+        for pair in &self.similar_dirs {
+            info!(
+                "Directories {} and {} are {:.0}% similar ({} and {} unique files)",
+                pair.left,
+                pair.right,
+                pair.similarity * 100.0,
+                pair.only_in_left.len(),
+                pair.only_in_right.len()
+            );
+        }
+        for cluster in &self.similar_images {
+            info!(
+                "Keeping {} ({}) over {} similar images",
+                cluster.kept,
+                cluster.kept_resolution,
+                cluster.similar.len()
+            );
+        }
+        for cluster in &self.similar_text {
+            let files: Vec<&str> = cluster.files.iter().map(|f| f.path.as_str()).collect();
+            info!("Similar text files: {}", files.join(", "));
+        }
+        if !self.empty_files.is_empty() {
+            info!("{} empty files found", self.empty_files.len());
+        }
+    }
+
+    /// Write the report as YAML to the given path
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let yaml_content = serde_yaml::to_string(self)?;
+        let mut file = fs::File::create(path)?;
+        file.write_all(yaml_content.as_bytes())?;
+        info!("Wrote duplicate report to {}", path);
+        Ok(())
+    }
+}
+
+/// What a run did, as opposed to what it found.
+///
+/// Lists the destination collisions the planner resolved, the existing files the
+/// mover ran into and the attributes it failed to preserve. Logged once the plan
+/// has run and optionally written to a YAML file with `--run-report`.
+#[derive(Serialize, Default)]
+pub struct RunReport {
+    /// Id of the run, for `yee-haw undo`; not set for dry runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// Whether the run was stopped by SIGINT or SIGTERM before finishing the plan
+    pub interrupted: bool,
+    pub operations_done: usize,
+    /// Operations left for `yee-haw resume` after an interruption
+    pub operations_remaining: usize,
+    /// Whether the run stopped early because of `--fail-fast` or `--max-errors`
+    pub error_limit_reached: bool,
+    /// Files copied, moved or linked into place
+    pub succeeded: usize,
+    pub skipped: Vec<SkippedOperation>,
+    pub failures: Vec<OperationFailure>,
+    /// Files moved into place whose source could not be deleted afterwards
+    pub sources_kept: Vec<OperationFailure>,
+    pub collisions: Vec<Collision>,
+    pub conflicts: Vec<Conflict>,
+    pub preserve_failures: Vec<PreserveFailure>,
+}
+
+/// A file attribute that `--preserve` could not carry over to a copy
+#[derive(Serialize, Clone, Debug)]
+pub struct PreserveFailure {
+    pub destination: String,
+    pub attribute: String,
+    pub error: String,
+}
+
+/// A file that was left where it is
+#[derive(Serialize, Clone, Debug)]
+pub struct SkippedOperation {
+    pub source: String,
+    pub destination: String,
+    pub reason: String,
+}
+
+/// An operation that failed, and why
+#[derive(Serialize, Clone, Debug)]
+pub struct OperationFailure {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub destination: String,
+    pub error: String,
+}
+
+/// A file that already existed at a destination and what was done about it
+#[derive(Serialize, Clone, Debug)]
+pub struct Conflict {
+    pub destination: String,
+    pub source: String,
+    pub resolution: String,
+}
+
+impl RunReport {
+    /// Add the collisions resolved while planning; files left in place count as skipped
+    pub fn add_plan(&mut self, plan: &Plan) {
+        self.collisions = plan.collisions.clone();
+        self.skipped.extend(plan.collisions.iter().filter(|c| c.renamed_to.is_none()).map(|c| {
+            SkippedOperation {
+                source: c.source.clone(),
+                destination: c.destination.clone(),
+                reason: format!("collides with {}", c.kept),
+            }
+        }));
+    }
+
+    /// Exit status for the run, see `exit_code`
+    pub fn exit_code(&self) -> i32 {
+        if self.interrupted {
+            exit_code::INTERRUPTED
+        } else if self.failures.is_empty() && self.sources_kept.is_empty() {
+            exit_code::SUCCESS
+        } else if self.succeeded == 0 && self.sources_kept.is_empty() {
+            exit_code::TOTAL_FAILURE
+        } else {
+            exit_code::PARTIAL_FAILURE
+        }
+    }
+
+    /// Print a table of what happened to every file, with the reasons for skips and failures.
+    /// Goes to stderr with the logs, it is meant for whoever watches the run.
+    pub fn print_summary(&self) {
+        eprintln!();
+        eprintln!("{:<16} {:>8}", "Result", "Files");
+        eprintln!("{:<16} {:>8}", "Succeeded", self.succeeded);
+        eprintln!("{:<16} {:>8}", "Skipped", self.skipped.len());
+        eprintln!("{:<16} {:>8}", "Failed", self.failures.len());
+        if !self.sources_kept.is_empty() {
+            eprintln!("{:<16} {:>8}", "Sources kept", self.sources_kept.len());
+        }
+
+        let mut reasons: BTreeMap<&str, usize> = BTreeMap::new();
+        for skipped in &self.skipped {
+            *reasons.entry(skipped.reason.as_str()).or_default() += 1;
+        }
+        if !reasons.is_empty() {
+            eprintln!();
+            eprintln!("Skipped:");
+            for (reason, count) in reasons {
+                eprintln!("  {:>6}  {}", count, reason);
+            }
+        }
+        if !self.failures.is_empty() || !self.sources_kept.is_empty() {
+            eprintln!();
+            eprintln!("Failed:");
+            for failure in self.failures.iter().chain(&self.sources_kept) {
+                eprintln!("  {}: {}", failure.destination, failure.error);
+            }
+        }
+        eprintln!();
+    }
+
+    /// Log the run report
+    pub fn log_summary(&self) {
+        for collision in &self.collisions {
+            match &collision.renamed_to {
+                Some(renamed_to) => warn!(
+                    "Collision: {} and {} both map to {}, renamed to {}",
+                    collision.kept, collision.source, collision.destination, renamed_to
+                ),
+                None => warn!(
+                    "Collision: {} and {} both map to {}, leaving {} in place",
+                    collision.kept, collision.source, collision.destination, collision.source
+                ),
+            }
+        }
+        for conflict in &self.conflicts {
+            info!(
+                "Conflict: {} already exists, {} ({})",
+                conflict.destination, conflict.resolution, conflict.source
+            );
+        }
+        for failure in &self.preserve_failures {
+            warn!(
+                "Failed to preserve {} on {}: {}",
+                failure.attribute, failure.destination, failure.error
+            );
+        }
+        if self.error_limit_reached {
+            warn!(
+                "Stopped after {} failures: {} operations done, {} not started. Finish it with `yee-haw resume {}`",
+                self.failures.len(),
+                self.operations_done,
+                self.operations_remaining,
+                self.run_id.as_deref().unwrap_or("<run-id>")
+            );
+        }
+        if self.interrupted {
+            warn!(
+                "Run interrupted: {} operations done, {} not started. Finish it with `yee-haw resume {}`",
+                self.operations_done,
+                self.operations_remaining,
+                self.run_id.as_deref().unwrap_or("<run-id>")
+            );
+        }
+    }
+
+    /// Write the run report as YAML to the given path
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let yaml_content = serde_yaml::to_string(self)?;
+        let mut file = fs::File::create(path)?;
+        file.write_all(yaml_content.as_bytes())?;
+        info!("Wrote run report to {}", path);
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 use glob::Pattern;
+use crate::progress;
 use crate::yee_file::YeeFile;
 
 /// First stage in our file copier. Will scan the provided
@@ -21,22 +22,32 @@ impl Scanner{
     let root_path = Path::new(root_dir);
 
     queue.push(PathBuf::from(root_dir));
+    let _progress = progress::start("Scanning", None, None);
     
     while let Some(dir_path) = queue.pop() {
         if let Ok(entries) = fs::read_dir(&dir_path) {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    
-                    if path.is_dir() {
-                        queue.push(path);
-                    } else if path.is_file() {
-                        // Check if the file matches the pattern
-                        if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-                            if pattern.matches(file_name) {
-                                if let Some(yee_file) = YeeFile::from_path(root_path, &path) {
-                                    files.push(yee_file);
+            for entry in entries.flatten() {
+                let path = entry.path();
+                
+                if path.is_dir() {
+                    queue.push(path);
+                } else if path.is_file() {
+                    // Check if the file matches the pattern
+                    if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
+                        if pattern.matches(file_name) {
+                            if let Some(mut yee_file) = YeeFile::from_path(root_path, &path) {
+                                if let Ok(metadata) = entry.metadata() {
+                                    yee_file.size = metadata.len();
+                                    #[cfg(unix)]
+                                    {
+                                        use std::os::unix::fs::MetadataExt;
+                                        yee_file.device = Some(metadata.dev());
+                                        yee_file.inode = Some(metadata.ino());
+                                    }
                                 }
+                                progress::file(&path.to_string_lossy());
+                                progress::advance(yee_file.size);
+                                files.push(yee_file);
                             }
                         }
                     }
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::path::Path;
+use image::imageops::{self, FilterType};
+use image::{GrayImage, ImageReader};
+use log::{debug, trace};
+use crate::args::ImageHashStyle;
+use crate::throttle;
+use crate::yee_file::YeeFile;
+
+/// Extensions we can decode without any system libraries
+const IMAGE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "webp", "gif"];
+
+/// Finds images that look the same even though their bytes differ (resized,
+/// recompressed or re-exported copies).
+///
+/// Each image is decoded and reduced to a 64-bit perceptual hash. Images whose
+/// hashes are within `max_distance` bits of each other end up in the same cluster,
+/// and the highest resolution copy of each cluster is the one that is kept.
+pub struct SimilarImages {
+    style: ImageHashStyle,
+    max_distance: u32,
+    min_size: u64,
+}
+
+/// A group of visually similar images
+#[derive(Clone, Debug)]
+pub struct ImageCluster {
+    /// Index into `members` of the copy to keep
+    pub kept: usize,
+    pub members: Vec<ImageMatch>,
+}
+
+/// One image in a cluster
+#[derive(Clone, Debug)]
+pub struct ImageMatch {
+    /// Index into the file list passed to `SimilarImages::find`
+    pub index: usize,
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    /// Hamming distance from the kept image's hash
+    pub distance: u32,
+    hash: u64,
+}
+
+impl SimilarImages {
+    /// Images smaller than `min_size` bytes are never clustered, like `--min-dedup-size`
+    pub fn new(style: ImageHashStyle, max_distance: u32, min_size: u64) -> Self {
+        Self { style, max_distance, min_size }
+    }
+
+    /// Hash every decodable image in `files` and cluster them by hash distance.
+    /// Only clusters with more than one image are returned. Hardlinks of another
+    /// file in `files` are left out: moving one aside frees nothing.
+    pub fn find(&self, files: &[YeeFile]) -> Vec<ImageCluster> {
+        let mut links: HashMap<(u64, u64), usize> = HashMap::new();
+        for link_id in files.iter().filter_map(YeeFile::link_id) {
+            *links.entry(link_id).or_default() += 1;
+        }
+
+        let mut images = Vec::new();
+        for (index, file) in files.iter().enumerate() {
+            if !IMAGE_EXTENSIONS.contains(&file.extension.to_lowercase().as_str())
+                || file.size < self.min_size
+                || file.link_id().is_some_and(|link_id| links[&link_id] > 1)
+            {
+                continue;
+            }
+            let path = file.source_path();
+            match self.hash_image(Path::new(&path)) {
+                Ok((hash, width, height)) => {
+                    trace!("Perceptual hash {:016x} ({}x{}) for {}", hash, width, height, path);
+                    images.push(ImageMatch { index, path, width, height, distance: 0, hash });
+                }
+                Err(e) => debug!("Failed to decode image {}: {}", path, e),
+            }
+        }
+        debug!("Computed perceptual hashes for {} images", images.len());
+
+        // Union every pair within the threshold, so clusters are connected components.
+        // Each image is looked up among the ones before it, then added to the tree.
+        let mut parent: Vec<usize> = (0..images.len()).collect();
+        let mut tree = BkTree::default();
+        for (i, image) in images.iter().enumerate() {
+            for j in tree.within(image.hash, self.max_distance) {
+                let (a, b) = (find_root(&mut parent, j), find_root(&mut parent, i));
+                if a != b {
+                    parent[b] = a;
+                }
+            }
+            tree.insert(image.hash, i);
+        }
+
+        let mut groups: Vec<Vec<ImageMatch>> = vec![Vec::new(); images.len()];
+        for (i, image) in images.into_iter().enumerate() {
+            let root = find_root(&mut parent, i);
+            groups[root].push(image);
+        }
+
+        groups
+            .into_iter()
+            .filter(|members| members.len() > 1)
+            .map(|mut members| {
+                // Keep the highest resolution copy, the first one seen on a tie
+                let kept = members
+                    .iter()
+                    .enumerate()
+                    .max_by(|(ia, a), (ib, b)| {
+                        (a.width as u64 * a.height as u64)
+                            .cmp(&(b.width as u64 * b.height as u64))
+                            .then(ib.cmp(ia))
+                    })
+                    .map(|(i, _)| i)
+                    .unwrap_or(0);
+                let kept_hash = members[kept].hash;
+                for member in members.iter_mut() {
+                    member.distance = (member.hash ^ kept_hash).count_ones();
+                }
+                ImageCluster { kept, members }
+            })
+            .collect()
+    }
+
+    /// Decode an image and return its perceptual hash and dimensions
+    fn hash_image(&self, path: &Path) -> anyhow::Result<(u64, u32, u32)> {
+        let image = ImageReader::new(throttle::open(path)?).with_guessed_format()?.decode()?;
+        let gray = image.to_luma8();
+        let hash = match self.style {
+            ImageHashStyle::Dhash => dhash(&gray),
+            ImageHashStyle::Phash => phash(&gray),
+        };
+        Ok((hash, image.width(), image.height()))
+    }
+}
+
+/// BK-tree over perceptual hashes with the Hamming distance as its metric. Every
+/// child is keyed by its distance from the parent, so by the triangle inequality a
+/// search only has to descend into children within `max_distance` of that distance.
+#[derive(Default)]
+struct BkTree {
+    /// Hash, value and children (by distance) of every node; the root is the first
+    nodes: Vec<(u64, usize, HashMap<u32, usize>)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, value: usize) {
+        let new = self.nodes.len();
+        if new > 0 {
+            let mut node = 0;
+            loop {
+                let distance = (self.nodes[node].0 ^ hash).count_ones();
+                match self.nodes[node].2.get(&distance) {
+                    Some(&child) => node = child,
+                    None => {
+                        self.nodes[node].2.insert(distance, new);
+                        break;
+                    }
+                }
+            }
+        }
+        self.nodes.push((hash, value, HashMap::new()));
+    }
+
+    /// Values of every hash within `max_distance` bits of `hash`
+    fn within(&self, hash: u64, max_distance: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack = match self.nodes.is_empty() {
+            true => vec![],
+            false => vec![0],
+        };
+        while let Some(node) = stack.pop() {
+            let (node_hash, value, children) = &self.nodes[node];
+            let distance = (node_hash ^ hash).count_ones();
+            if distance <= max_distance {
+                found.push(*value);
+            }
+            let range = distance.saturating_sub(max_distance)..=distance + max_distance;
+            stack.extend(children.iter().filter(|(d, _)| range.contains(d)).map(|(_, &child)| child));
+        }
+        found
+    }
+}
+
+/// Union-find lookup with path halving, shared with the other similarity passes
+pub(crate) fn find_root(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Difference hash: shrink to 9x8 and record whether each pixel is brighter
+/// than its right-hand neighbour
+fn dhash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(gray, 9, 8, FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Perceptual hash: shrink to 32x32, take the 2D DCT and record whether each of
+/// the lowest 8x8 frequencies is above their median
+fn phash(gray: &GrayImage) -> u64 {
+    const SIZE: usize = 32;
+    let small = imageops::resize(gray, SIZE as u32, SIZE as u32, FilterType::Triangle);
+    let pixels: Vec<f64> = small.pixels().map(|p| p[0] as f64).collect();
+
+    let cosines: Vec<f64> = (0..8 * SIZE)
+        .map(|i| {
+            let (u, x) = (i / SIZE, i % SIZE);
+            (std::f64::consts::PI * (2 * x + 1) as f64 * u as f64 / (2 * SIZE) as f64).cos()
+        })
+        .collect();
+
+    let mut coefficients = [0f64; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    sum += pixels[y * SIZE + x] * cosines[u * SIZE + x] * cosines[v * SIZE + y];
+                }
+            }
+            coefficients[v * 8 + u] = sum;
+        }
+    }
+
+    // The DC term only reflects overall brightness, leave it out of the median
+    let mut sorted: Vec<f64> = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+
+    coefficients
+        .iter()
+        .fold(0u64, |hash, &c| (hash << 1) | (c > median) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bk_tree_finds_hashes_within_distance() {
+        let hashes = [0u64, 0b1, 0b11, 0b1111, u64::MAX, u64::MAX - 1, 0xf0f0];
+        let mut tree = BkTree::default();
+        for (i, &hash) in hashes.iter().enumerate() {
+            tree.insert(hash, i);
+        }
+        for &query in &[0u64, 0b111, u64::MAX, 0xff00] {
+            for max_distance in 0..6 {
+                let mut found = tree.within(query, max_distance);
+                found.sort();
+                let expected: Vec<usize> = (0..hashes.len())
+                    .filter(|&i| (hashes[i] ^ query).count_ones() <= max_distance)
+                    .collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+}
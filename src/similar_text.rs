@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use log::{debug, trace};
+use xxhash_rust::xxh3::{xxh3_64, xxh3_64_with_seed};
+use crate::similar_images::find_root;
+use crate::throttle;
+use crate::yee_file::YeeFile;
+
+/// Files larger than this are not treated as text documents
+const MAX_TEXT_SIZE: u64 = 32 * 1024 * 1024;
+/// How much of a file is checked for text before the rest is read
+const SNIFF_SIZE: u64 = 8 * 1024;
+/// Number of words in each shingle
+const SHINGLE_WORDS: usize = 5;
+/// Number of MinHash functions in each signature
+const NUM_HASHES: usize = 128;
+/// LSH bands; with 4 rows each, pairs above ~40% similarity are likely to collide
+const BANDS: usize = 32;
+const ROWS: usize = NUM_HASHES / BANDS;
+
+/// Finds near-identical text documents, like contract revisions or the same
+/// report saved with different whitespace.
+///
+/// Each text file is normalized and split into overlapping word shingles, which
+/// are summarized by a MinHash signature. Locality-sensitive hashing over the
+/// signatures picks candidate pairs, and pairs whose shingle sets have a Jaccard
+/// similarity of at least `threshold` are clustered together.
+pub struct SimilarText {
+    threshold: f64,
+}
+
+/// A group of similar text files
+#[derive(Clone, Debug)]
+pub struct TextCluster {
+    pub members: Vec<TextMatch>,
+}
+
+/// One file in a text cluster
+#[derive(Clone, Debug)]
+pub struct TextMatch {
+    pub path: String,
+    /// Jaccard similarity with the first member of the cluster
+    pub similarity: f64,
+}
+
+struct Document {
+    path: String,
+    shingles: HashSet<u64>,
+    signature: Vec<u64>,
+}
+
+impl SimilarText {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold }
+    }
+
+    /// Compare every text file in `files` and return the clusters of similar ones.
+    /// Exact copies should already have been removed by the store.
+    pub fn find(&self, files: &[YeeFile]) -> Vec<TextCluster> {
+        let mut documents = Vec::new();
+        for file in files {
+            let path = file.source_path();
+            match read_text(Path::new(&path)) {
+                Ok(Some(text)) => {
+                    let shingles = shingles(&text);
+                    if shingles.is_empty() {
+                        continue;
+                    }
+                    let signature = minhash(&shingles);
+                    documents.push(Document { path, shingles, signature });
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Failed to read text from {}: {}", path, e),
+            }
+        }
+        debug!("Computed MinHash signatures for {} text files", documents.len());
+
+        // Documents that share any band of their signature are candidates
+        let mut buckets: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+        for (index, document) in documents.iter().enumerate() {
+            for band in 0..BANDS {
+                let rows = &document.signature[band * ROWS..(band + 1) * ROWS];
+                let key: Vec<u8> = rows.iter().flat_map(|r| r.to_le_bytes()).collect();
+                buckets.entry((band, xxh3_64(&key))).or_default().push(index);
+            }
+        }
+
+        let mut parent: Vec<usize> = (0..documents.len()).collect();
+        let mut compared = HashSet::new();
+        for bucket in buckets.values() {
+            for (i, &a) in bucket.iter().enumerate() {
+                for &b in &bucket[i + 1..] {
+                    if !compared.insert((a, b)) {
+                        continue;
+                    }
+                    let similarity = jaccard(&documents[a].shingles, &documents[b].shingles);
+                    trace!(
+                        "{} and {} are {:.0}% similar",
+                        documents[a].path,
+                        documents[b].path,
+                        similarity * 100.0
+                    );
+                    if similarity >= self.threshold {
+                        let (root_a, root_b) = (find_root(&mut parent, a), find_root(&mut parent, b));
+                        if root_a != root_b {
+                            parent[root_b] = root_a;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut groups: Vec<Vec<usize>> = vec![Vec::new(); documents.len()];
+        for index in 0..documents.len() {
+            let root = find_root(&mut parent, index);
+            groups[root].push(index);
+        }
+
+        groups
+            .into_iter()
+            .filter(|members| members.len() > 1)
+            .map(|members| {
+                let first = &documents[members[0]];
+                TextCluster {
+                    members: members
+                        .iter()
+                        .map(|&i| TextMatch {
+                            path: documents[i].path.clone(),
+                            similarity: jaccard(&first.shingles, &documents[i].shingles),
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Hash of a text file with whitespace runs and line endings normalized away.
+/// Returns `None` for files that don't look like text.
+pub fn normalized_text_hash(path: &Path) -> anyhow::Result<Option<String>> {
+    Ok(read_text(path)?.map(|text| format!("{:016x}", xxh3_64(normalize(&text).as_bytes()))))
+}
+
+/// Read a file as text, or `None` if it is too large, not UTF-8 or contains NUL bytes.
+/// Only the first `SNIFF_SIZE` bytes are read from files that are clearly binary.
+fn read_text(path: &Path) -> anyhow::Result<Option<String>> {
+    let size = fs::metadata(path)?.len();
+    if size > MAX_TEXT_SIZE {
+        return Ok(None);
+    }
+    let mut reader = throttle::open(path)?;
+    let mut bytes = Vec::with_capacity(size as usize);
+    reader.by_ref().take(SNIFF_SIZE).read_to_end(&mut bytes)?;
+    if !looks_like_text(&bytes) {
+        return Ok(None);
+    }
+    reader.read_to_end(&mut bytes)?;
+    if bytes.contains(&0) {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(bytes).ok())
+}
+
+/// Whether the start of a file could be text: no NUL bytes and valid UTF-8, except
+/// for a character cut off at the end
+fn looks_like_text(start: &[u8]) -> bool {
+    if start.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(start) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Collapse every run of whitespace (including line endings) into a single space
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Hashes of every run of `SHINGLE_WORDS` consecutive lowercase words.
+/// Short documents become a single shingle of all their words.
+fn shingles(text: &str) -> HashSet<u64> {
+    let words: Vec<String> = text.split_whitespace().map(str::to_lowercase).collect();
+    if words.is_empty() {
+        return HashSet::new();
+    }
+    words
+        .windows(SHINGLE_WORDS.min(words.len()))
+        .map(|window| xxh3_64(window.join(" ").as_bytes()))
+        .collect()
+}
+
+/// MinHash signature: the smallest seeded hash of any shingle, for each seed
+fn minhash(shingles: &HashSet<u64>) -> Vec<u64> {
+    (0..NUM_HASHES as u64)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|shingle| xxh3_64_with_seed(&shingle.to_le_bytes(), seed))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+fn jaccard(a: &HashSet<u64>, b: &HashSet<u64>) -> f64 {
+    let shared = a.intersection(b).count();
+    shared as f64 / (a.len() + b.len() - shared) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_text_before_reading_it() {
+        assert!(looks_like_text(b"plain text"));
+        assert!(!looks_like_text(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert!(!looks_like_text(b"caf\xff text"));
+        // A character split by the end of the sniffed bytes is fine
+        assert!(looks_like_text(&"café".as_bytes()[..4]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let text = "é".repeat(SNIFF_SIZE as usize);
+        fs::write(dir.path().join("a.txt"), &text).unwrap();
+        assert_eq!(read_text(&dir.path().join("a.txt")).unwrap(), Some(text));
+        let mut binary = b"text".repeat(SNIFF_SIZE as usize);
+        binary.push(0);
+        fs::write(dir.path().join("b.bin"), &binary).unwrap();
+        assert_eq!(read_text(&dir.path().join("b.bin")).unwrap(), None);
+    }
+}
@@ -1,5 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
 use log::{debug, info, trace};
+use crate::args::KeepPolicy;
+use crate::image_key;
 use crate::yee_file::YeeFile;
 
 /// Final stage that stores files and detects duplicates based on hash
@@ -9,27 +14,62 @@ pub struct Store {
     originals: Vec<YeeFile>,
     /// Duplicate files
     duplicates: Vec<YeeFile>,
+    /// Index into `originals` for each entry in `duplicates`
+    duplicate_of: Vec<usize>,
     /// HashMap to track file hashes for faster duplicate detection
     hash_map: HashMap<String, usize>,
+    /// Files smaller than this are always kept as originals
+    min_dedup_size: u64,
+}
+
+/// A group of files sharing the same hash: the kept original and its duplicates.
+/// Holds indices into the store so it can be used to drive `Store::keep_duplicate`.
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    pub original: usize,
+    pub duplicates: Vec<usize>,
 }
 
 impl Store {
-    /// Create a new empty store
-    pub fn new() -> Self {
+    /// Create a new empty store that only dedupes files of at least `min_dedup_size` bytes
+    pub fn new(min_dedup_size: u64) -> Self {
         Self {
             originals: Vec::new(),
             duplicates: Vec::new(),
+            duplicate_of: Vec::new(),
             hash_map: HashMap::new(),
+            min_dedup_size,
         }
     }
 
-    /// Insert a file into the store, detecting duplicates by hash
+    /// Insert a file into the store, detecting duplicates by its dedup key or hash
     /// Returns true if the file was a duplicate, false otherwise
     pub fn insert(&mut self, file: YeeFile) -> bool {
+        // Tiny files (placeholders like empty __init__.py or .keep) match too easily to dedupe
+        if file.size < self.min_dedup_size {
+            trace!(
+                "File {}.{} is below the minimum dedup size, treating as original",
+                file.filename, file.extension
+            );
+            self.originals.push(file);
+            return false;
+        }
+        
         // Check if the file has a hash
-        if let Some(hash) = &file.hash {
+        if let Some(hash) = file.dedup_key.as_ref().or(file.hash.as_ref()) {
             // Check if we've seen this hash before
             if let Some(&original_index) = self.hash_map.get(hash) {
+                // A hardlink of the original shares its data, moving it aside frees nothing
+                let original = &self.originals[original_index];
+                if file.link_id().is_some() && file.link_id() == original.link_id() {
+                    debug!(
+                        "Already linked: {}.{} is a hardlink of {}.{}",
+                        file.filename, file.extension, original.filename, original.extension
+                    );
+                    self.originals.push(file);
+                    return false;
+                }
+                
                 // This is a duplicate
                 debug!(
                     "Found duplicate: {}.{} (hash: {})",
@@ -42,7 +82,8 @@ impl Store {
                 );
                 
                 self.duplicates.push(file);
-                return true;
+                self.duplicate_of.push(original_index);
+                true
             } else {
                 // This is a new file
                 trace!(
@@ -52,7 +93,7 @@ impl Store {
                 let index = self.originals.len();
                 self.hash_map.insert(hash.clone(), index);
                 self.originals.push(file);
-                return false;
+                false
             }
         } else {
             // No hash, treat as original
@@ -61,7 +102,7 @@ impl Store {
                 file.filename, file.extension
             );
             self.originals.push(file);
-            return false;
+            false
         }
     }
 
@@ -83,6 +124,161 @@ impl Store {
         );
     }
 
+    /// Keep every file as an original without looking for duplicates,
+    /// for runs that don't track them
+    pub fn keep_all(&mut self, files: Vec<YeeFile>) {
+        self.originals.extend(files);
+    }
+
+    /// Group duplicates with the original they were matched against.
+    /// Only originals with at least one duplicate are returned, in insertion order.
+    pub fn clusters(&self) -> Vec<Cluster> {
+        let mut by_original: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (dup_index, &original_index) in self.duplicate_of.iter().enumerate() {
+            by_original.entry(original_index).or_default().push(dup_index);
+        }
+
+        let mut clusters: Vec<Cluster> = by_original
+            .into_iter()
+            .map(|(original, duplicates)| Cluster { original, duplicates })
+            .collect();
+        clusters.sort_by_key(|c| c.original);
+        clusters
+    }
+
+    /// Make the duplicate at `dup_index` the kept copy of its cluster.
+    /// The previous original takes its place among the duplicates.
+    pub fn keep_duplicate(&mut self, dup_index: usize) {
+        let original_index = self.duplicate_of[dup_index];
+        std::mem::swap(
+            &mut self.originals[original_index],
+            &mut self.duplicates[dup_index],
+        );
+        debug!(
+            "Keeping {}.{} instead of {}.{}",
+            self.originals[original_index].filename,
+            self.originals[original_index].extension,
+            self.duplicates[dup_index].filename,
+            self.duplicates[dup_index].extension
+        );
+    }
+
+    /// Groups of files that are hardlinks of each other, across originals and duplicates
+    pub fn linked_groups(&self) -> Vec<Vec<&YeeFile>> {
+        let mut by_link: HashMap<(u64, u64), Vec<&YeeFile>> = HashMap::new();
+        for file in self.originals.iter().chain(self.duplicates.iter()) {
+            if let Some(link_id) = file.link_id() {
+                by_link.entry(link_id).or_default().push(file);
+            }
+        }
+        let mut groups: Vec<Vec<&YeeFile>> = by_link.into_values().filter(|g| g.len() > 1).collect();
+        groups.sort_by_key(|g| (g[0].source_full_path.clone(), g[0].filename.clone()));
+        groups
+    }
+
+    /// Bytes that removing the duplicates would free. Hardlinked duplicates only
+    /// count once, and not at all if they share their data with a kept original.
+    pub fn wasted_bytes(&self) -> u64 {
+        let mut counted: HashSet<(u64, u64)> = self.originals.iter().filter_map(YeeFile::link_id).collect();
+        self.duplicates
+            .iter()
+            .filter(|file| file.link_id().is_none_or(|link_id| counted.insert(link_id)))
+            .map(|file| file.size)
+            .sum()
+    }
+
+    /// Re-pick the kept copy of every cluster according to the keep policy.
+    /// Ties leave the current original in place.
+    pub fn apply_keep_policy(&mut self, policy: KeepPolicy) {
+        if policy == KeepPolicy::First {
+            return;
+        }
+
+        let score = |file: &YeeFile| -> u128 {
+            let path = file.source_path();
+            let Ok(metadata) = fs::metadata(&path) else {
+                return 0;
+            };
+            // Metadata segments of JPEG and PNG files, the whole size of anything else
+            let metadata_bytes = || {
+                image_key::metadata_bytes(Path::new(&path), &file.extension)
+                    .ok()
+                    .flatten()
+                    .unwrap_or(metadata.len()) as u128
+            };
+            match policy {
+                KeepPolicy::MostMetadata => metadata_bytes(),
+                KeepPolicy::LeastMetadata => u128::MAX - metadata_bytes(),
+                KeepPolicy::Newest => metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_nanos()),
+                KeepPolicy::First => 0,
+            }
+        };
+
+        for cluster in self.clusters() {
+            let mut best_score = score(&self.originals[cluster.original]);
+            let mut best = None;
+            for &dup_index in &cluster.duplicates {
+                let dup_score = score(&self.duplicates[dup_index]);
+                if dup_score > best_score {
+                    best_score = dup_score;
+                    best = Some(dup_index);
+                }
+            }
+            if let Some(dup_index) = best {
+                self.keep_duplicate(dup_index);
+            }
+        }
+    }
+
+    /// Move originals into the duplicates, each as a duplicate of another original.
+    /// Takes `(demoted, kept)` pairs of indices into `originals`; indices held from
+    /// before the call are invalidated since the originals shift down.
+    pub fn demote(&mut self, demotions: &[(usize, usize)]) {
+        let targets: HashMap<usize, usize> = demotions.iter().copied().collect();
+
+        // Work out where every remaining original ends up
+        let mut remap = vec![0; self.originals.len()];
+        let mut kept = Vec::new();
+        let mut demoted = Vec::new();
+        for (index, file) in std::mem::take(&mut self.originals).into_iter().enumerate() {
+            if targets.contains_key(&index) {
+                demoted.push((index, file));
+            } else {
+                remap[index] = kept.len();
+                kept.push(file);
+            }
+        }
+        for (index, _) in &demoted {
+            remap[*index] = remap[targets[index]];
+        }
+
+        self.originals = kept;
+        for original_index in self.duplicate_of.iter_mut() {
+            *original_index = remap[*original_index];
+        }
+        for original_index in self.hash_map.values_mut() {
+            *original_index = remap[*original_index];
+        }
+        for (index, file) in demoted {
+            debug!("Demoting {}.{} to a duplicate", file.filename, file.extension);
+            self.duplicates.push(file);
+            self.duplicate_of.push(remap[index]);
+        }
+    }
+
+    /// Each duplicate paired with the original it matched
+    pub fn duplicate_pairs(&self) -> Vec<(&YeeFile, &YeeFile)> {
+        self.duplicates
+            .iter()
+            .zip(&self.duplicate_of)
+            .map(|(dup, &original_index)| (dup, &self.originals[original_index]))
+            .collect()
+    }
+
     /// Get a reference to the original files
     pub fn originals(&self) -> &Vec<YeeFile> {
         &self.originals
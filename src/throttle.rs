@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The `--bwlimit` and `--ops-limit` rates, shared by every thread that hashes or copies
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Smallest piece of data copied at once under `--bwlimit`
+const MIN_CHUNK: usize = 64 * 1024;
+
+struct Limits {
+    /// Bytes read while hashing or copied, per second
+    bytes: Option<Bucket>,
+    /// Files hashed and plan operations run, per second
+    ops: Option<Bucket>,
+}
+
+/// Hands out `rate` units per second, saving up at most a second's worth. A caller
+/// takes what it needs and sleeps off any debt, so the threads sharing a bucket
+/// stay under the rate together.
+struct Bucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    available: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self { rate, state: Mutex::new(BucketState { available: rate, refilled: Instant::now() }) }
+    }
+
+    fn take(&self, amount: u64) {
+        let wait = {
+            let mut state = self.lock();
+            let now = Instant::now();
+            let earned = now.duration_since(state.refilled).as_secs_f64() * self.rate;
+            state.available = (state.available + earned).min(self.rate) - amount as f64;
+            state.refilled = now;
+            if state.available >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.available / self.rate)
+        };
+        thread::sleep(wait);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BucketState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Set the limits for the rest of the run. Without them every call here returns at once.
+pub fn configure(bytes_per_second: Option<u64>, ops_per_second: Option<u64>) {
+    let _ = LIMITS.set(Limits { bytes: bytes_per_second.map(Bucket::new), ops: ops_per_second.map(Bucket::new) });
+}
+
+/// Wait until `count` more bytes can be read or written under `--bwlimit`
+pub fn bytes(count: u64) {
+    if let Some(bucket) = LIMITS.get().and_then(|limits| limits.bytes.as_ref()) {
+        bucket.take(count);
+    }
+}
+
+/// Wait until one more file can be hashed, or plan operation run, under `--ops-limit`
+pub fn op() {
+    if let Some(bucket) = LIMITS.get().and_then(|limits| limits.ops.as_ref()) {
+        bucket.take(1);
+    }
+}
+
+/// Reads through `inner`, waiting under `--bwlimit` for the bytes read
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl<R> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        bytes(count as u64);
+        Ok(count)
+    }
+}
+
+impl<R: Seek> Seek for Reader<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(position)
+    }
+}
+
+/// Open a file for buffered reading under `--bwlimit`
+pub fn open(path: &Path) -> io::Result<BufReader<Reader<File>>> {
+    Ok(BufReader::new(Reader::new(File::open(path)?)))
+}
+
+/// Read a whole file like `fs::read`, under `--bwlimit`
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let mut data = Vec::with_capacity(file.metadata().map_or(0, |metadata| metadata.len() as usize));
+    Reader::new(file).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// How much of a file to copy at once: `largest`, or a tenth of a second's worth of
+/// `--bwlimit` so the rate stays smooth
+pub fn chunk(largest: usize) -> usize {
+    match LIMITS.get().and_then(|limits| limits.bytes.as_ref()) {
+        Some(bucket) => (bucket.rate as usize / 10).clamp(MIN_CHUNK, largest.max(MIN_CHUNK)),
+        None => largest,
+    }
+}
+
+/// Give the disks and the CPU to everything else first, for `--nice`: the idle I/O
+/// scheduling class on Linux and the lowest CPU priority on Unix. Threads started
+/// afterwards inherit both.
+pub fn lower_priority() -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+        let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(unix)]
+    {
+        // The lowest priority, like `nice -n 19`
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if cfg!(unix) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"))
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use log::{debug, trace};
+use xxhash_rust::xxh3::xxh3_64;
+use crate::yee_file::YeeFile;
+
+/// Directory-level view of the scanned files, used to find whole folders that
+/// are copies of each other.
+///
+/// Every directory gets a Merkle-style hash built from the content hashes of its
+/// files and the hashes of its subdirectories, so two folders with the same
+/// contents hash the same regardless of where they live or what they are called.
+pub struct DirTree {
+    dirs: BTreeMap<PathBuf, DirNode>,
+}
+
+#[derive(Default)]
+struct DirNode {
+    /// Content hashes and paths of the files directly in this directory
+    files: Vec<(String, PathBuf)>,
+    /// Immediate subdirectories
+    children: BTreeSet<PathBuf>,
+    /// Merkle hash over files and children, filled in by `DirTree::build`
+    hash: String,
+    /// Every file hash found in this directory or below it, with the first file that had it
+    contents: BTreeMap<String, PathBuf>,
+    /// Number of files in this directory or below it
+    file_count: usize,
+}
+
+/// A set of directories whose whole subtrees are identical
+#[derive(Clone, Debug)]
+pub struct IdenticalDirs {
+    pub hash: String,
+    pub file_count: usize,
+    pub dirs: Vec<PathBuf>,
+}
+
+/// A directory whose files all exist somewhere under another directory
+#[derive(Clone, Debug)]
+pub struct SupersetDir {
+    pub superset: PathBuf,
+    pub subset: PathBuf,
+    pub file_count: usize,
+}
+
+/// Two directories that share most, but not all, of their files
+#[derive(Clone, Debug)]
+pub struct SimilarDirs {
+    pub left: PathBuf,
+    pub right: PathBuf,
+    /// Jaccard similarity of the two sets of file hashes
+    pub similarity: f64,
+    pub only_in_left: Vec<PathBuf>,
+    pub only_in_right: Vec<PathBuf>,
+}
+
+impl DirTree {
+    /// Build the tree from files that already have their hashes set.
+    /// Files without a hash are ignored, as their content is unknown.
+    pub fn build(root_dir: &str, files: &[YeeFile]) -> Self {
+        let root = PathBuf::from(root_dir);
+        let mut dirs: BTreeMap<PathBuf, DirNode> = BTreeMap::new();
+        dirs.entry(root.clone()).or_default();
+
+        for file in files {
+            let Some(hash) = &file.hash else {
+                continue;
+            };
+            let dir = PathBuf::from(&file.source_full_path);
+            let path = dir.join(format!("{}.{}", file.filename, file.extension));
+            dirs.entry(dir.clone()).or_default().files.push((hash.clone(), path));
+
+            // Register the directory with each of its ancestors up to the scan root
+            let mut child = dir;
+            while child != root {
+                let Some(parent) = child.parent().map(Path::to_path_buf) else {
+                    break;
+                };
+                if !parent.starts_with(&root) {
+                    break;
+                }
+                dirs.entry(parent.clone()).or_default().children.insert(child);
+                child = parent;
+            }
+        }
+
+        let mut tree = Self { dirs };
+        tree.hash_dir(&root);
+        debug!("Built directory tree with {} directories", tree.dirs.len());
+        tree
+    }
+
+    /// Compute the hash, contents and file count of a directory, children first
+    fn hash_dir(&mut self, dir: &Path) {
+        let children: Vec<PathBuf> = self.dirs[dir].children.iter().cloned().collect();
+        for child in &children {
+            self.hash_dir(child);
+        }
+
+        // Sort the entries so the hash doesn't depend on scan order
+        let mut entries: Vec<String> = self.dirs[dir].files.iter().map(|(h, _)| format!("f:{}", h)).collect();
+        let mut contents: BTreeMap<String, PathBuf> = BTreeMap::new();
+        for (hash, path) in &self.dirs[dir].files {
+            contents.entry(hash.clone()).or_insert_with(|| path.clone());
+        }
+        let mut file_count = self.dirs[dir].files.len();
+        for child in &children {
+            let child_node = &self.dirs[child];
+            entries.push(format!("d:{}", child_node.hash));
+            for (hash, path) in &child_node.contents {
+                contents.entry(hash.clone()).or_insert_with(|| path.clone());
+            }
+            file_count += child_node.file_count;
+        }
+        entries.sort();
+
+        let hash = format!("{:016x}", xxh3_64(entries.join("\n").as_bytes()));
+        trace!("Directory {} hashed to {}", dir.display(), hash);
+
+        let node = self.dirs.get_mut(dir).expect("directory registered during build");
+        node.hash = hash;
+        node.contents = contents;
+        node.file_count = file_count;
+    }
+
+    /// Groups of directories with identical subtrees.
+    ///
+    /// Only the topmost match is reported: if the parents of every directory in a
+    /// group are themselves identical, the group is covered by its parents' group.
+    pub fn identical(&self) -> Vec<IdenticalDirs> {
+        let mut by_hash: HashMap<&str, Vec<&PathBuf>> = HashMap::new();
+        for (path, node) in &self.dirs {
+            if node.file_count > 0 {
+                by_hash.entry(node.hash.as_str()).or_default().push(path);
+            }
+        }
+
+        let mut groups: Vec<IdenticalDirs> = by_hash
+            .into_iter()
+            .filter(|(_, dirs)| dirs.len() > 1)
+            .filter(|(_, dirs)| !self.covered_by_parents(dirs))
+            .map(|(hash, dirs)| IdenticalDirs {
+                hash: hash.to_string(),
+                file_count: self.dirs[dirs[0]].file_count,
+                dirs: dirs.into_iter().cloned().collect(),
+            })
+            .collect();
+        groups.sort_by(|a, b| b.file_count.cmp(&a.file_count).then(a.dirs.cmp(&b.dirs)));
+        groups
+    }
+
+    /// True if the directories have distinct parents that are all identical too
+    fn covered_by_parents(&self, dirs: &[&PathBuf]) -> bool {
+        let parents: BTreeSet<&Path> = dirs.iter().filter_map(|d| d.parent()).collect();
+        if parents.len() != dirs.len() {
+            return false;
+        }
+        let hashes: Option<BTreeSet<&str>> = parents
+            .iter()
+            .map(|p| self.dirs.get(*p).map(|node| node.hash.as_str()))
+            .collect();
+        hashes.is_some_and(|h| h.len() == 1)
+    }
+
+    /// True if the two directories are unrelated and every file under `inner`
+    /// also exists under `outer`
+    fn contained_in(&self, inner: &Path, outer: &Path) -> bool {
+        if inner.starts_with(outer) || outer.starts_with(inner) {
+            return false;
+        }
+        match (self.dirs.get(inner), self.dirs.get(outer)) {
+            (Some(i), Some(o)) => {
+                i.file_count > 0 && i.contents.keys().all(|hash| o.contents.contains_key(hash))
+            }
+            _ => false,
+        }
+    }
+
+    /// Jaccard similarity of the file hashes under two unrelated directories
+    fn similarity(&self, left: &Path, right: &Path) -> Option<f64> {
+        if left.starts_with(right) || right.starts_with(left) {
+            return None;
+        }
+        let (l, r) = (self.dirs.get(left)?, self.dirs.get(right)?);
+        if l.contents.is_empty() || r.contents.is_empty() {
+            return None;
+        }
+        let shared = l.contents.keys().filter(|hash| r.contents.contains_key(*hash)).count();
+        let union = l.contents.len() + r.contents.len() - shared;
+        Some(shared as f64 / union as f64)
+    }
+
+    /// Directories holding each file hash somewhere below them, so the comparisons
+    /// only look at pairs of directories that share a file
+    fn dirs_by_hash(&self) -> HashMap<&str, Vec<&PathBuf>> {
+        let mut by_hash: HashMap<&str, Vec<&PathBuf>> = HashMap::new();
+        for (path, node) in &self.dirs {
+            for hash in node.contents.keys() {
+                by_hash.entry(hash.as_str()).or_default().push(path);
+            }
+        }
+        by_hash
+    }
+
+    /// Directories whose files are all contained in another, unrelated directory.
+    ///
+    /// Identical pairs and ancestor/descendant pairs are skipped. A subset is only
+    /// reported when its parent isn't also contained in the same superset, and only
+    /// against the deepest directory that still contains it.
+    pub fn supersets(&self) -> Vec<SupersetDir> {
+        let mut results = Vec::new();
+        let by_hash = self.dirs_by_hash();
+
+        for (subset_path, subset) in &self.dirs {
+            // A superset holds every file of the subset, the rarest one narrows them down most
+            let rarest = subset.contents.keys().map(|hash| &by_hash[hash.as_str()]).min_by_key(|dirs| dirs.len());
+            let Some(candidates) = rarest else {
+                continue;
+            };
+            for &superset_path in candidates {
+                let superset = &self.dirs[superset_path];
+                if superset.file_count <= subset.file_count
+                    || superset.hash == subset.hash
+                    || !self.contained_in(subset_path, superset_path)
+                {
+                    continue;
+                }
+
+                // Prefer reporting the largest contained folder in the smallest container
+                if subset_path
+                    .parent()
+                    .is_some_and(|parent| self.contained_in(parent, superset_path))
+                    || superset.children.iter().any(|child| self.contained_in(subset_path, child))
+                {
+                    continue;
+                }
+
+                results.push(SupersetDir {
+                    superset: superset_path.clone(),
+                    subset: subset_path.clone(),
+                    file_count: subset.file_count,
+                });
+            }
+        }
+
+        results.sort_by(|a, b| b.file_count.cmp(&a.file_count).then(a.subset.cmp(&b.subset)));
+        results
+    }
+
+    /// True if the parent directory holds exactly the same set of files, making
+    /// this directory a pass-through that adds nothing to a comparison
+    fn same_as_parent(&self, dir: &Path) -> bool {
+        let parent = dir.parent().and_then(|p| self.dirs.get(p));
+        match (self.dirs.get(dir), parent) {
+            (Some(node), Some(parent)) => node.contents.len() == parent.contents.len(),
+            _ => false,
+        }
+    }
+
+    /// Pairs of unrelated directories whose file hash sets overlap by at least
+    /// `threshold` (Jaccard similarity) and where each side has files the other lacks.
+    /// Pure subsets are left to `supersets`.
+    ///
+    /// A pair is skipped when the two parents already form a similar pair, so a
+    /// half-merged backup is reported once at its top level. Directories without a
+    /// file in common are never compared.
+    pub fn similar(&self, threshold: f64) -> Vec<SimilarDirs> {
+        let qualifies = |left: &Path, right: &Path| {
+            self.similarity(left, right)
+                .is_some_and(|similarity| similarity >= threshold && similarity < 1.0)
+        };
+
+        let mut results = Vec::new();
+        let by_hash = self.dirs_by_hash();
+        let dirs: Vec<&PathBuf> = self.dirs.keys().filter(|d| !self.same_as_parent(d)).collect();
+        for left in &dirs {
+            // Directories without a file in common have a similarity of zero
+            let candidates: BTreeSet<&PathBuf> = self.dirs[*left]
+                .contents
+                .keys()
+                .flat_map(|hash| by_hash[hash.as_str()].iter().copied())
+                .filter(|right| right > left && !self.same_as_parent(right))
+                .collect();
+            for right in &candidates {
+                let Some(similarity) = self.similarity(left, right) else {
+                    continue;
+                };
+                if similarity < threshold || similarity >= 1.0 {
+                    continue;
+                }
+                if let (Some(left_parent), Some(right_parent)) = (left.parent(), right.parent()) {
+                    if left_parent != right_parent && qualifies(left_parent, right_parent) {
+                        continue;
+                    }
+                }
+
+                let (l, r) = (&self.dirs[*left], &self.dirs[*right]);
+                let only_in = |a: &DirNode, b: &DirNode| -> Vec<PathBuf> {
+                    a.contents
+                        .iter()
+                        .filter(|(hash, _)| !b.contents.contains_key(*hash))
+                        .map(|(_, path)| path.clone())
+                        .collect()
+                };
+                let (only_in_left, only_in_right) = (only_in(l, r), only_in(r, l));
+                if only_in_left.is_empty() || only_in_right.is_empty() {
+                    continue;
+                }
+                trace!(
+                    "Directories {} and {} are {:.0}% similar",
+                    left.display(),
+                    right.display(),
+                    similarity * 100.0
+                );
+
+                results.push(SimilarDirs {
+                    left: (*left).clone(),
+                    right: (*right).clone(),
+                    similarity,
+                    only_in_left,
+                    only_in_right,
+                });
+            }
+        }
+
+        results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity).then(a.left.cmp(&b.left)));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(files: &[(&str, &str)]) -> DirTree {
+        let files: Vec<YeeFile> = files
+            .iter()
+            .map(|(path, hash)| {
+                let mut file = YeeFile::from_path(Path::new("/r"), Path::new(path)).unwrap();
+                file.hash = Some(hash.to_string());
+                file
+            })
+            .collect();
+        DirTree::build("/r", &files)
+    }
+
+    #[test]
+    fn finds_supersets_and_similar_dirs_sharing_files() {
+        let tree = tree(&[
+            ("/r/a/1.txt", "h1"),
+            ("/r/a/2.txt", "h2"),
+            ("/r/a/3.txt", "h3"),
+            ("/r/b/1.txt", "h1"),
+            ("/r/b/2.txt", "h2"),
+            ("/r/c/1.txt", "h1"),
+            ("/r/c/2.txt", "h2"),
+            ("/r/c/4.txt", "h4"),
+            ("/r/d/5.txt", "h5"),
+        ]);
+
+        let supersets: Vec<(PathBuf, PathBuf)> =
+            tree.supersets().into_iter().map(|s| (s.subset, s.superset)).collect();
+        assert_eq!(
+            supersets,
+            vec![("/r/b".into(), "/r/a".into()), ("/r/b".into(), "/r/c".into())]
+        );
+
+        let similar = tree.similar(0.5);
+        assert_eq!(similar.len(), 1);
+        assert_eq!((similar[0].left.as_path(), similar[0].right.as_path()), (Path::new("/r/a"), Path::new("/r/c")));
+        assert_eq!(similar[0].only_in_left, vec![PathBuf::from("/r/a/3.txt")]);
+        assert_eq!(similar[0].only_in_right, vec![PathBuf::from("/r/c/4.txt")]);
+        // /r/d shares nothing with the others, not even at a threshold of zero
+        assert!(tree.similar(0.0).iter().all(|pair| pair.left != Path::new("/r/d") && pair.right != Path::new("/r/d")));
+    }
+}
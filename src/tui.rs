@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use log::debug;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, List, ListItem, ListState, Paragraph, Row, Table, Tabs};
+use ratatui::{DefaultTerminal, Frame};
+use crate::journal::civil_from_days;
+use crate::plan::{OpKind, Plan, Planner};
+use crate::store::{Cluster, Store};
+use crate::yee_file::YeeFile;
+
+/// Interactive review screen shown before the mover runs.
+///
+/// Lets the user walk through duplicate clusters, compare the copies side by side,
+/// pick which copy to keep and browse the resulting destination tree. The caller
+/// only proceeds to move files if `run` returns true.
+pub struct Review<'a> {
+    store: &'a mut Store,
+    planner: &'a Planner,
+    tab: Tab,
+    /// Duplicate clusters of the store, and the destination tree of the plan built from
+    /// it, both refreshed only when the kept copy of a cluster changes
+    clusters: Vec<Cluster>,
+    tree: Vec<Line<'static>>,
+    selected: ListState,
+    copy: usize,
+    tree_scroll: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Tab {
+    Duplicates,
+    Plan,
+}
+
+impl<'a> Review<'a> {
+    pub fn new(store: &'a mut Store, planner: &'a Planner) -> Self {
+        let mut review = Self {
+            store,
+            planner,
+            tab: Tab::Duplicates,
+            clusters: Vec::new(),
+            tree: Vec::new(),
+            selected: ListState::default(),
+            copy: 0,
+            tree_scroll: 0,
+        };
+        review.refresh();
+        match review.clusters.is_empty() {
+            // Nothing to review on the duplicates tab, start on the plan
+            true => review.tab = Tab::Plan,
+            false => review.selected.select(Some(0)),
+        }
+        review
+    }
+
+    /// Recompute the clusters and rebuild the plan after the store changed
+    fn refresh(&mut self) {
+        self.clusters = self.store.clusters();
+        self.tree = match self.planner.build(self.store.originals(), &self.store.duplicate_pairs()) {
+            Ok(plan) => plan_tree(&plan),
+            Err(e) => vec![Line::from(Span::styled(e.to_string(), Style::default().fg(Color::Red)))],
+        };
+    }
+
+    /// Take over the terminal until the user confirms or aborts.
+    /// Returns true if the plan was confirmed.
+    pub fn run(mut self) -> anyhow::Result<bool> {
+        let mut terminal = ratatui::init();
+        let result = self.event_loop(&mut terminal);
+        ratatui::restore();
+        result
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> anyhow::Result<bool> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    debug!("Review aborted by user");
+                    return Ok(false);
+                }
+                KeyCode::Enter | KeyCode::Char('y') => {
+                    debug!("Review confirmed by user");
+                    return Ok(true);
+                }
+                KeyCode::Tab => {
+                    self.tab = match self.tab {
+                        Tab::Duplicates => Tab::Plan,
+                        Tab::Plan => Tab::Duplicates,
+                    };
+                }
+                KeyCode::Up | KeyCode::Char('k') => self.scroll(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.scroll(1),
+                KeyCode::PageUp => self.scroll(-10),
+                KeyCode::PageDown => self.scroll(10),
+                KeyCode::Left | KeyCode::Char('h') => self.copy = self.copy.saturating_sub(1),
+                KeyCode::Right | KeyCode::Char('l') => {
+                    if let Some(copies) = self.selected_copies() {
+                        self.copy = (self.copy + 1).min(copies - 1);
+                    }
+                }
+                KeyCode::Char(' ') => self.keep_selected(),
+                _ => {}
+            }
+        }
+    }
+
+    fn scroll(&mut self, delta: i32) {
+        match self.tab {
+            Tab::Duplicates => {
+                let count = self.clusters.len();
+                if count == 0 {
+                    return;
+                }
+                let current = self.selected.selected().unwrap_or(0) as i32;
+                let next = (current + delta).clamp(0, count as i32 - 1) as usize;
+                if Some(next) != self.selected.selected() {
+                    self.selected.select(Some(next));
+                    self.copy = 0;
+                }
+            }
+            Tab::Plan => {
+                self.tree_scroll = (self.tree_scroll as i32 + delta).max(0) as u16;
+            }
+        }
+    }
+
+    /// Number of copies (original + duplicates) in the selected cluster
+    fn selected_copies(&self) -> Option<usize> {
+        let index = self.selected.selected()?;
+        self.clusters.get(index).map(|c| c.duplicates.len() + 1)
+    }
+
+    /// Make the highlighted copy the one that is kept for its cluster
+    fn keep_selected(&mut self) {
+        if self.tab != Tab::Duplicates || self.copy == 0 {
+            return;
+        }
+        let Some(index) = self.selected.selected() else {
+            return;
+        };
+        if let Some(&dup_index) = self.clusters[index].duplicates.get(self.copy - 1) {
+            self.store.keep_duplicate(dup_index);
+            // The chosen copy now sits in the original slot
+            self.copy = 0;
+            self.refresh();
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3), Constraint::Min(0), Constraint::Length(1)])
+            .split(frame.area());
+
+        let titles = vec![
+            format!("Duplicates ({})", self.clusters.len()),
+            format!("Plan ({} files)", self.store.total_count()),
+        ];
+        let tabs = Tabs::new(titles)
+            .block(Block::default().borders(Borders::ALL).title("yee-haw review"))
+            .select(match self.tab {
+                Tab::Duplicates => 0,
+                Tab::Plan => 1,
+            })
+            .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD));
+        frame.render_widget(tabs, chunks[0]);
+
+        match self.tab {
+            Tab::Duplicates => self.draw_duplicates(frame, chunks[1]),
+            Tab::Plan => self.draw_plan(frame, chunks[1]),
+        }
+
+        let help = match self.tab {
+            Tab::Duplicates => "↑/↓ cluster  ←/→ copy  space keep copy  tab plan  enter confirm  q abort",
+            Tab::Plan => "↑/↓ scroll  tab duplicates  enter confirm  q abort",
+        };
+        frame.render_widget(
+            Paragraph::new(help).style(Style::default().fg(Color::DarkGray)),
+            chunks[2],
+        );
+    }
+
+    fn draw_duplicates(&mut self, frame: &mut Frame, area: Rect) {
+        let chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(30), Constraint::Percentage(70)])
+            .split(area);
+
+        let items: Vec<ListItem> = self
+            .clusters
+            .iter()
+            .map(|cluster| {
+                let original = &self.store.originals()[cluster.original];
+                ListItem::new(format!(
+                    "{}.{} ×{}",
+                    original.destination_filename,
+                    original.extension,
+                    cluster.duplicates.len() + 1
+                ))
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Clusters"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, chunks[0], &mut self.selected);
+
+        let Some(cluster) = self.selected.selected().and_then(|i| self.clusters.get(i)) else {
+            frame.render_widget(
+                Paragraph::new("No duplicates found")
+                    .block(Block::default().borders(Borders::ALL).title("Details")),
+                chunks[1],
+            );
+            return;
+        };
+
+        // Kept copy first, then its duplicates, one column each
+        let mut copies = vec![&self.store.originals()[cluster.original]];
+        copies.extend(cluster.duplicates.iter().map(|&i| &self.store.duplicates()[i]));
+
+        let fields = ["Role", "Name", "Source", "Group", "Destination", "Size", "Modified", "Hash"];
+        let rows: Vec<Row> = fields
+            .iter()
+            .enumerate()
+            .map(|(field_index, field)| {
+                let mut cells = vec![Cell::from(*field).style(Style::default().add_modifier(Modifier::BOLD))];
+                for (copy_index, file) in copies.iter().enumerate() {
+                    let value = copy_field(file, field_index, copy_index == 0, self.planner);
+                    let style = if copy_index == self.copy {
+                        Style::default().fg(Color::Yellow)
+                    } else {
+                        Style::default()
+                    };
+                    cells.push(Cell::from(value).style(style));
+                }
+                Row::new(cells)
+            })
+            .collect();
+
+        let mut widths = vec![Constraint::Length(12)];
+        widths.extend(copies.iter().map(|_| Constraint::Fill(1)));
+
+        let table = Table::new(rows, widths)
+            .block(Block::default().borders(Borders::ALL).title("Copies"));
+        frame.render_widget(table, chunks[1]);
+    }
+
+    fn draw_plan(&self, frame: &mut Frame, area: Rect) {
+        let paragraph = Paragraph::new(self.tree.clone())
+            .block(Block::default().borders(Borders::ALL).title("Destination tree"))
+            .scroll((self.tree_scroll, 0));
+        frame.render_widget(paragraph, area);
+    }
+}
+
+/// Value for one row of the side-by-side comparison table
+fn copy_field(file: &YeeFile, field: usize, kept: bool, planner: &Planner) -> String {
+    let source_path = file.source_path();
+    match field {
+        0 => if kept { "KEEP".to_string() } else { "dupe".to_string() },
+        1 => format!("{}.{}", file.destination_filename, file.extension),
+        2 => file.source_full_path.clone(),
+        3 => file.group_id.clone(),
+        4 => if kept {
+            file.destination_full_path.clone()
+        } else {
+            planner.dupe_directory(file).to_string_lossy().to_string()
+        },
+        5 => fs::metadata(&source_path)
+            .map(|m| format!("{} bytes", m.len()))
+            .unwrap_or_else(|_| "-".to_string()),
+        6 => fs::metadata(&source_path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| format_date(d.as_secs()))
+            .unwrap_or_else(|| "-".to_string()),
+        _ => file.hash.clone().unwrap_or_else(|| "none".to_string()),
+    }
+}
+
+/// `2025-01-01 12:00:00 UTC` for a time in seconds since the Unix epoch
+fn format_date(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Render the planned destination folders and the files that will land in each
+fn plan_tree(plan: &Plan) -> Vec<Line<'static>> {
+    let mut tree: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    for operation in &plan.operations {
+        if !matches!(operation.kind, OpKind::Copy | OpKind::Move | OpKind::Link) {
+            continue;
+        }
+        let destination = Path::new(&operation.destination);
+        let dir = destination.parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+        let name = destination.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let source = operation.source.clone().or_else(|| operation.link_to.clone()).unwrap_or_default();
+        tree.entry(dir).or_default().push((name, source));
+    }
+
+    let mut lines = Vec::new();
+    for (dir, mut files) in tree {
+        files.sort();
+        lines.push(Line::from(Span::styled(
+            format!("{}/", dir),
+            Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD),
+        )));
+        for (name, source) in files {
+            lines.push(Line::from(vec![
+                Span::raw(format!("    {}", name)),
+                Span::styled(format!("  ← {}", source), Style::default().fg(Color::DarkGray)),
+            ]));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_modified_time_as_date() {
+        assert_eq!(format_date(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_date(1_709_210_096), "2024-02-29 12:34:56 UTC");
+    }
+}
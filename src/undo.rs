@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use anyhow::bail;
+use log::{debug, error, info, warn};
+use crate::args::YeeArgs;
+use crate::copy;
+use crate::exit_code::{self, ConfigError};
+use crate::journal::{self, Action, JournalEntry, Phase};
+use crate::mover::safe_move;
+use crate::preserve;
+
+/// Reverses a run from its journal: files go back to their sources, and the
+/// folders and metadata the run created are removed.
+///
+/// Nothing is touched if any file the run wrote has changed or disappeared since.
+pub struct Undo {
+    args: YeeArgs,
+}
+
+impl Undo {
+    pub fn new(args: YeeArgs) -> Self {
+        Self { args }
+    }
+
+    /// Undo the run with the given id, whose journal is in the destination directory
+    /// Returns the exit status: a partial failure if some operations couldn't be reverted.
+    pub fn run(&self, run_id: &str) -> anyhow::Result<i32> {
+        let path = journal::journal_path(&self.args.destination_dir, run_id);
+        if !path.exists() {
+            return Err(ConfigError(format!("No journal for run {} in {}", run_id, self.args.destination_dir)).into());
+        }
+
+        // The last entry of each operation says how far it got
+        let mut operations: BTreeMap<usize, JournalEntry> = BTreeMap::new();
+        for entry in journal::read(&path)? {
+            operations.insert(entry.seq, entry);
+        }
+        let completed: Vec<&JournalEntry> = operations
+            .values()
+            .filter(|entry| match entry.phase {
+                Phase::Done => true,
+                Phase::Failed => false,
+                Phase::Begin => {
+                    let done = entry.looks_done();
+                    warn!(
+                        "Operation {} ({:?} {}) was interrupted, {}",
+                        entry.seq,
+                        entry.action,
+                        entry.destination,
+                        if done { "it completed" } else { "it did not complete" }
+                    );
+                    done
+                }
+            })
+            .collect();
+        info!("Undoing {} operations of run {}", completed.len(), run_id);
+
+        let changed: Vec<String> = completed.iter().filter_map(|entry| check(entry).err()).collect();
+        if !changed.is_empty() {
+            for problem in &changed {
+                error!("{}", problem);
+            }
+            bail!("{} files changed since run {}, refusing to undo it", changed.len(), run_id);
+        }
+
+        let mut failures = 0;
+        for entry in completed.iter().rev() {
+            if entry.replaced {
+                warn!("{} replaced an existing file, which can't be restored", entry.destination);
+            }
+            if let Err(e) = self.revert(entry) {
+                warn!("Failed to undo {:?} {}: {}", entry.action, entry.destination, e);
+                failures += 1;
+            }
+        }
+
+        // Keep the journal around, but make sure the run isn't undone twice
+        let undone = path.with_extension("jsonl.undone");
+        fs::rename(&path, &undone)?;
+        info!("Run {} undone with {} failures, journal kept at {}", run_id, failures, undone.display());
+        Ok(if failures == 0 { exit_code::SUCCESS } else { exit_code::PARTIAL_FAILURE })
+    }
+
+    /// Reverse a single operation
+    fn revert(&self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let destination = entry.destination.as_str();
+        match (entry.action, entry.source.as_deref()) {
+            (Action::Mkdir, _) => {
+                // Anything else put in the folder since the run stays, and so does the folder
+                if let Err(e) = fs::remove_dir(destination) {
+                    warn!("Leaving directory {} in place: {}", destination, e);
+                }
+            }
+            (Action::WriteMetadata, _) | (Action::Copy, _) | (Action::Link, None) => {
+                fs::remove_file(destination)?;
+            }
+            (Action::Move, Some(source)) => {
+                create_parent(source)?;
+                let moved = safe_move(destination, source, entry.hash.as_deref(), &self.args.preserve, self.args.copy_engine)?;
+                if let Some(error) = moved.source_kept {
+                    warn!("{} was restored but is still at {}: {}", source, destination, error);
+                }
+            }
+            (Action::Link, Some(source)) => {
+                create_parent(source)?;
+                fs::hard_link(destination, source)?;
+                fs::remove_file(destination)?;
+            }
+            (Action::RemoveSource, Some(source)) => {
+                create_parent(source)?;
+                copy::copy_file(Path::new(destination), Path::new(source), self.args.copy_engine)?;
+                let failures = preserve::apply(Path::new(destination), Path::new(source), &self.args.preserve);
+                for failure in failures {
+                    warn!("Failed to preserve {} on {}: {}", failure.attribute, source, failure.error);
+                }
+            }
+            (action, None) => bail!("{:?} has no source to restore", action),
+        }
+        debug!("Undid {:?} {}", entry.action, destination);
+        Ok(())
+    }
+}
+
+/// Check that what the operation left behind is still there, unchanged
+fn check(entry: &JournalEntry) -> Result<(), String> {
+    let destination = Path::new(&entry.destination);
+    match entry.action {
+        Action::Mkdir | Action::WriteMetadata => return Ok(()),
+        Action::Copy | Action::Move | Action::Link | Action::RemoveSource => {}
+    }
+
+    if !destination.exists() {
+        return Err(format!("{} no longer exists", entry.destination));
+    }
+    if !entry.destination_matches() {
+        return Err(format!("{} changed since the run", entry.destination));
+    }
+    if entry.action != Action::Copy {
+        if let Some(source) = entry.source.as_deref().filter(|source| Path::new(source).exists()) {
+            return Err(format!("{} exists again, restoring it would overwrite it", source));
+        }
+    }
+    Ok(())
+}
+
+fn create_parent(path: &str) -> anyhow::Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::journal::Journal;
+    use crate::meta::hash_file;
+    use tempfile::TempDir;
+
+    /// A run that moved one file into `organized`, with its undo
+    fn moved_file() -> (TempDir, Undo, String, String, String) {
+        let dir = TempDir::new().unwrap();
+        let path = |path: &Path| path.to_string_lossy().to_string();
+        let source = path(&dir.path().join("photo.jpg"));
+        let destination_dir = path(&dir.path().join("organized"));
+        let destination = path(&dir.path().join("organized").join("photo.jpg"));
+        fs::create_dir(&destination_dir).unwrap();
+        fs::write(&source, b"the photo").unwrap();
+
+        let hash = hash_file(Path::new(&source)).unwrap();
+        let mut journal = Journal::create(&destination_dir).unwrap();
+        let entry = journal.begin(0, Action::Move, Some(&source), &destination, Some(&hash)).unwrap();
+        fs::rename(&source, &destination).unwrap();
+        journal.done(entry).unwrap();
+
+        let undo = Undo::new(YeeArgs::parse_from(["yee-haw", "-d", &destination_dir]));
+        (dir, undo, journal.run_id().to_string(), source, destination)
+    }
+
+    #[test]
+    fn undo_moves_file_back() {
+        let (_dir, undo, run_id, source, destination) = moved_file();
+        assert_eq!(undo.run(&run_id).unwrap(), exit_code::SUCCESS);
+        assert_eq!(fs::read(&source).unwrap(), b"the photo");
+        assert!(!Path::new(&destination).exists());
+    }
+
+    #[test]
+    fn undo_refuses_when_a_file_changed() {
+        let (_dir, undo, run_id, source, destination) = moved_file();
+        fs::write(&destination, b"the edited photo").unwrap();
+
+        assert!(undo.run(&run_id).is_err());
+        assert!(!Path::new(&source).exists());
+        assert_eq!(fs::read(&destination).unwrap(), b"the edited photo");
+        // Nothing was undone, so it can be tried again once the file is put back
+        assert!(journal::journal_path(&undo.args.destination_dir, &run_id).exists());
+    }
+}
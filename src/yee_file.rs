@@ -2,7 +2,7 @@ use std::path::Path;
 use log::{debug, trace};
 use serde::{Serialize, Deserialize};
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct YeeFile{
     // Name of file without extension or path 
     pub filename: String,
@@ -13,14 +13,32 @@ pub struct YeeFile{
     // Full path to the file excluding filename + extension
     pub destination_full_path: String,
 
+    // Name of file at the destination, without extension, set by the rename style
+    #[serde(default)]
+    pub destination_filename: String,
+
     // Path with the root of the scan removed
     pub source_local_path: String,
     // Path with the root of the storage removed
     pub destination_local_path: String,
 
+    // Size of the file in bytes
+    #[serde(default)]
+    pub size: u64,
+
+    // Device and inode of the file, shared by hardlinks of the same data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+
     // Hash of the file
     pub hash: Option<String>,
 
+    // Key used to detect duplicates when it differs from the hash (e.g. image data only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_key: Option<String>,
+
     // Is based off source_local_path as this defines the group. Its hashed and used
     // to generate a short hash.
     pub group_id: String,
@@ -67,14 +85,34 @@ impl YeeFile {
                file_name, group_id, parent_folder);
         
         Some(Self {
+            destination_filename: file_name.clone(),
             filename: file_name,
             extension,
             source_full_path,
             destination_full_path,
             source_local_path,
             destination_local_path,
+            size: 0,
+            device: None,
+            inode: None,
             hash: None,
+            dedup_key: None,
             group_id,
         })
     }
+
+    /// Full path of the source file
+    pub fn source_path(&self) -> String {
+        format!("{}/{}.{}", self.source_full_path, self.filename, self.extension)
+    }
+
+    /// Full path the file is organized to, once Meta has set the destination
+    pub fn destination_path(&self) -> String {
+        format!("{}/{}.{}", self.destination_full_path, self.destination_filename, self.extension)
+    }
+
+    /// Identity of the underlying data: files with the same link id are hardlinks
+    pub fn link_id(&self) -> Option<(u64, u64)> {
+        Some((self.device?, self.inode?))
+    }
 }